@@ -1,27 +1,40 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryInto;
 use std::fs;
 use std::io::Read;
+use std::ops::Bound;
 use std::path::Path;
+use std::sync::Arc;
 use std::sync::mpsc::Sender;
 
 use actix_web::web::Bytes;
-use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
 use jsonpath_lib::Selector;
-use lmdb::{Cursor, Database, DatabaseFlags, Environment, EnvironmentFlags, RoTransaction, RwTransaction, Transaction, WriteFlags};
+use lmdb::{Cursor, Database, DatabaseFlags, Environment, EnvironmentFlags, RwTransaction, Transaction, WriteFlags};
+use lmdb_sys::{MDB_FIRST, MDB_LAST, MDB_NEXT, MDB_PREV, MDB_SET_RANGE};
 use log::{debug, info, trace, warn};
 use rmps::Serializer;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
+use ulid::Ulid;
+use uuid::Uuid;
 
 use crate::errors::BarnError::{DbConfigError, EnvOpenError, TxCommitError};
 use crate::errors::BarnError;
+use crate::normalizer::{self, Normalizer};
 use crate::schema;
 use crate::conf::*;
+use crate::query::{self, Aggregate, AggregateOp, Condition, Op, Query, Sort};
 
 const DB_PRIMARY_KEY_KEY : [u8; 8] = 0_i64.to_le_bytes();
 const DB_READ_START_KEY : [u8; 8] = 1_i64.to_le_bytes();
 const PK_WRITE_FLAGS: WriteFlags = WriteFlags::empty();
+/// version of a newly inserted resource
+pub const FIRST_VERSION: u64 = 1;
+/// version of the layout of the stored resources and index keys, stored in the main DB under FORMAT_VERSION_KEY.
+/// Version 1 had neither the version of the resources nor the marker, environments without it are of version 1
+pub const FORMAT_VERSION: u64 = 2;
+const FORMAT_VERSION_KEY: &str = "#format_version";
 
 pub struct Barn {
     env: Environment,
@@ -33,20 +46,100 @@ struct Barrel {
     db: Database,
     id_attr_name: String,
     id_attr_type: String,
+    id_strategy: IdStrategy,
+    // maps the external ids to PKs, present for all the strategies other than sequence
+    ids_db: Option<Database>,
+    metadata: MetadataConf,
+    // formats of the stamped creation and modification times, derived from the schema
+    created_at_format: TimeFormat,
+    updated_at_format: TimeFormat,
     indices: HashMap<String, Index>,
     flags: WriteFlags
 }
 
+/// The versions a precondition accepts
+#[derive(Debug, Clone)]
+pub enum VersionMatch {
+    Any,
+    Versions(Vec<u64>)
+}
+
+/// Conditions on the current version of a resource, checked in the same transaction that modifies it
+#[derive(Debug, Clone, Default)]
+pub struct Precondition {
+    pub if_match: Option<VersionMatch>,
+    pub if_none_match: Option<VersionMatch>
+}
+
+/// Outcome of an upsert
+#[derive(Debug)]
+pub struct Upserted {
+    pub pk: u64,
+    pub version: u64,
+    // false if an existing resource was updated
+    pub created: bool
+}
+
+/// A read-write transaction spanning all the barrels, obtained through `Barn::transaction`
+pub struct Txn<'a> {
+    barrels: &'a HashMap<String, Barrel>,
+    tx: RwTransaction<'a>
+}
+
+struct Candidates<'a> {
+    // the index whose entries are visited, in the order of the keys or in the reverse order
+    index: &'a Index,
+    range: KeyRange,
+    descending: bool,
+    // PKs of the resources selected using another index when the entries come from the sort index
+    selected: Option<HashSet<u64>>,
+    // true if the resources missing from the sort index are visited after its entries
+    with_missing: bool,
+    // attributes whose conditions were fully applied while finding the candidates
+    served: Vec<&'a str>
+}
+
 struct Index {
     db: Database,
+    name: String,
     unique: bool,
+    // resources missing any of the attributes are indexed as if the attribute was null
+    index_missing: bool,
+    // the indexed attributes, more than one for compound indices
+    parts: Vec<KeyPart>,
+    flags: WriteFlags
+    //key_maker: KeyMaker
+}
+
+struct KeyPart {
     at_path: String,
+    // type and format of the attribute, or of the elements for array attributes
     val_type: String,
     val_format: String,
-    flags: WriteFlags
-    //key_maker: KeyMaker
+    // true for array attributes, each element is indexed under its own key
+    multi: bool,
+    // applied to string values, shared by all the attributes of an index
+    normalizer: Arc<dyn Normalizer>
 }
 
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+// the values of the indexed attributes of a resource and the key made of them
+type IndexEntry<'r> = (Vec<&'r Value>, Vec<u8>);
+
+#[derive(Clone, Copy)]
+enum TimeFormat {
+    // milliseconds since epoch
+    Millis,
+    // RFC 3339 string in UTC
+    DateTime
+}
+
+// key of null values, sorts before the keys of all the other values
+const NULL_KEY: [u8; 1] = [0];
+
+static NULL: Value = Value::Null;
+
 impl Barn {
     pub fn open<R>(env_dir: &str, db_conf: &DbConf, schema_rdr: R) -> Result<Barn, BarnError>
     where R: Read {
@@ -86,7 +179,8 @@ impl Barn {
             }
         }
 
-        let tx = env.begin_rw_txn().unwrap();
+        let mut tx = env.begin_rw_txn().unwrap();
+        check_format_version(&mut tx)?;
         for rname in &res_names.unwrap() {
             let res_conf = db_conf.resources.get(rname);
 
@@ -107,6 +201,8 @@ impl Barn {
                 Some(v) => {
                     let mut id_attr_name = String::from("");
                     let mut id_attr_type = String::from("");
+                    let mut id_strategy = IdStrategy::Sequence;
+                    let mut metadata = MetadataConf::default();
                     if res_conf.is_some() {
                         let res_conf = res_conf.unwrap();
                         if res_conf.id_attr_name.is_some() {
@@ -117,36 +213,27 @@ impl Barn {
                             id_attr_type = res_conf.id_attr_type.as_ref().unwrap().clone();
                         }
 
+                        id_strategy = res_conf.id_strategy;
+                        metadata = res_conf.metadata.clone();
+
                         for i in &res_conf.indices {
-                            let at_path = i.attr_path.replace(".", "/");
-                            let at_pointer = format!("/properties/{}", &at_path);
-                            let at_def = v.pointer(at_pointer.as_str()).unwrap().as_object().unwrap();
-                            let at_type_val : &str;
-                            let mut at_type_format : &str = "";
-                            let at_type = at_def.get(&String::from("type"));
-                            if let Some(t) = at_type {
-                                at_type_val = at_type.unwrap().as_str().unwrap();
-                                let at_format = at_def.get("format");
-                                if at_format.is_some() {
-                                    at_type_format = at_format.unwrap().as_str().unwrap();
-                                }
-                            }
-                            else {
-                                let at_ref = at_def.get(&String::from("$ref")).unwrap();
-                                let at_def = schema.pointer(at_ref.as_str().unwrap().strip_prefix("#").unwrap()).unwrap().as_object().unwrap();
-                                at_type_val = at_def.get(&String::from("type")).unwrap().as_str().unwrap();
-                                let at_format = at_def.get("format");
-                                if at_format.is_some() {
-                                    at_type_format = at_format.unwrap().as_str().unwrap();
+                            let normalizer_name = i.normalizer.as_deref().unwrap_or("");
+                            let normalizer: Arc<dyn Normalizer> = match normalizer::from_name(normalizer_name) {
+                                Some(n) => Arc::from(n),
+                                None => {
+                                    warn!("unknown normalizer {} configured for the index on {}", normalizer_name, i.attr_path.name());
+                                    return Err(DbConfigError);
                                 }
-                            }
+                            };
+                            let parts = i.attr_path.paths().into_iter().map(|p| key_part(&schema, v, p, normalizer.clone())).collect();
                             let mut unique = false;
                             if let Some(u) = i.unique {
                                 unique = u;
                             }
+                            let index_missing = i.index_missing.unwrap_or(false);
 
                             unsafe {
-                                let index_name = format!("{}_{}", rname, &i.attr_path);
+                                let index_name = format!("{}_{}", rname, i.attr_path.name());
                                 let mut write_flags = WriteFlags::empty();
                                 let mut db_flags = DatabaseFlags::empty();
                                 if !unique {
@@ -158,14 +245,12 @@ impl Barn {
                                 }
 
                                 let db = tx.create_db(Some(index_name.as_str()), db_flags).unwrap();
-                                // prefix with a slash to make it a valid pointer for the object
-                                let at_path = format!("/{}", &at_path);
                                 let idx = Index{
                                     db,
+                                    name: index_name.clone(),
                                     unique,
-                                    at_path,
-                                    val_type: String::from(at_type_val),
-                                    val_format: String::from(at_type_format),
+                                    index_missing,
+                                    parts,
                                     flags: write_flags
                                 };
 
@@ -182,14 +267,27 @@ impl Barn {
                         id_attr_type = db_conf.resource_defaults.id_attr_type.clone();
                     }
 
+                    let created_at_format = time_format(&schema, v, metadata.created_at.as_deref())?;
+                    let updated_at_format = time_format(&schema, v, metadata.updated_at.as_deref())?;
+
                     // create resource level DB
                     unsafe {
                         let db = tx.create_db(Some(rname.as_str()), DatabaseFlags::INTEGER_KEY).unwrap();
+                        let mut ids_db = None;
+                        if id_strategy != IdStrategy::Sequence {
+                            let ids_db_name = format!("{}#ids", rname);
+                            ids_db = Some(tx.create_db(Some(ids_db_name.as_str()), DatabaseFlags::empty()).unwrap());
+                        }
                         let barrel = Barrel{
                             db,
                             indices,
                             id_attr_name,
                             id_attr_type,
+                            id_strategy,
+                            ids_db,
+                            metadata,
+                            created_at_format,
+                            updated_at_format,
                             flags: WriteFlags::NO_OVERWRITE
                         };
                         barrels.insert(rname.clone(), barrel);
@@ -216,7 +314,9 @@ impl Barn {
         }
     }
 
-    pub fn insert(&self, res_name: String, r: &mut Value) -> Result<(), BarnError> {
+    /// Inserts the resource and returns the assigned PK, the resource's id attribute is set to the
+    /// same value
+    pub fn insert(&self, res_name: String, r: &mut Value) -> Result<u64, BarnError> {
         let barrel = self.barrels.get(res_name.as_str());
         if let None = barrel {
             return Err(BarnError::UnknownResourceName);
@@ -228,10 +328,10 @@ impl Barn {
             Ok(mut tx) => {
                 let barrel_result = barrel.unwrap().insert(&mut tx, r);
                 match barrel_result {
-                    Ok(_) => {
+                    Ok(pk) => {
                         match tx.commit() {
                             Ok(_) => {
-                                Ok(())
+                                Ok(pk)
                             },
                             Err(e) => {
                                 warn!("failed to insert resource {}", e);
@@ -252,243 +352,2159 @@ impl Barn {
         }
     }
 
-    pub fn get(&self, id: u64, res_name: String) -> Result<Value, BarnError> {
-        let barrel = self.barrels.get(res_name.as_str());
-        if let None = barrel {
-            return Err(BarnError::UnknownResourceName);
-        }
-
-        let tx_result = self.env.begin_ro_txn();
-        match tx_result {
-            Ok(tx) => {
-                let val_result = barrel.unwrap().get(id, &tx);
-                let _ = tx.commit();
-                val_result
-            },
-            Err(e) => {
-                Err(BarnError::TxBeginError)
-            }
-        }
-    }
-
-    pub fn search(&self, res_name: String, expr: String, sn: Sender<Result<Bytes, std::io::Error>>) -> Result<(), BarnError> {
+    /// Inserts the resources in transactions of batch_size resources each, all the resources are
+    /// inserted in a single transaction if batch_size is 0. A failure to insert a resource does not
+    /// affect the other resources. Returns the PK assigned to each resource or the error that
+    /// prevented its insertion, in the same order as the given resources.
+    pub fn insert_many(&self, res_name: String, records: &mut [Value], batch_size: usize) -> Result<Vec<Result<u64, BarnError>>, BarnError> {
         let barrel = self.barrels.get(res_name.as_str());
         if let None = barrel {
             return Err(BarnError::UnknownResourceName);
         }
 
-        let tx_result = self.env.begin_ro_txn();
-        if let Err(e) = tx_result {
-            return Err(BarnError::TxBeginError);
-        }
-
-        let mut compiled_path = jsonpath_lib::compile(expr.as_str());
-
         let barrel = barrel.unwrap();
-        let tx = tx_result.unwrap();
-        let cursor = tx.open_ro_cursor(barrel.db);
-        if let Err(e) = cursor {
-            return Err(BarnError::TxReadError);
+        let mut results = vec!();
+        let mut batch_size = batch_size;
+        if batch_size == 0 {
+            batch_size = records.len().max(1);
         }
 
-        let send_result = sn.send(Ok(Bytes::from_static(b"[")));
-
-        let mut send_comma = false;
-        // the first row will always be key 0 which stores the PK value, and will be skipped
-        for row in cursor.unwrap().iter_from(DB_READ_START_KEY) {
-            if let Err(e) = row {
-                break;
+        for batch in records.chunks_mut(batch_size) {
+            let tx_result = self.env.begin_rw_txn();
+            if let Err(e) = tx_result {
+                warn!("failed to begin transaction {}", e);
+                return Err(BarnError::TxBeginError);
             }
 
-            let (key, data) = row.unwrap();
-            let json_val: Value = rmps::from_read_ref(data).unwrap();
-            let result = compiled_path(&json_val);
-            if result.is_ok() {
-                if result.unwrap().len() == 0 {
+            let mut tx = tx_result.unwrap();
+            let mut batch_results = vec!();
+            for r in batch.iter_mut() {
+                // each resource is inserted in a nested transaction to discard the partial writes of a failed insert
+                let nested_result = tx.begin_nested_txn();
+                if let Err(e) = nested_result {
+                    warn!("failed to begin nested transaction {}", e);
+                    batch_results.push(Err(BarnError::TxBeginError));
                     continue;
                 }
-                let str_result = serde_json::to_vec(&json_val);
-                match str_result {
-                    Ok(vec) => {
-                        if send_comma {
-                            let send_result = sn.send(Ok(Bytes::from_static(b",")));
-                        }
-                        let send_result = sn.send(Ok(Bytes::from(vec)));
-                        if let Err(e) = send_result {
-                            warn!("error received while sending search results {:?}", e);
-                            break;
+
+                let mut nested = nested_result.unwrap();
+                match barrel.insert(&mut nested, r) {
+                    Ok(pk) => {
+                        match nested.commit() {
+                            Ok(_) => batch_results.push(Ok(pk)),
+                            Err(e) => {
+                                warn!("failed to commit nested transaction {}", e);
+                                batch_results.push(Err(BarnError::TxCommitError));
+                            }
                         }
-                        send_comma = true;
-                    }
+                    },
                     Err(e) => {
-                        warn!("failed to convert the result to string, stopping further processing {:?}", e);
-                        break;
+                        debug!("failed to insert resource {}", e);
+                        nested.abort();
+                        batch_results.push(Err(e));
+                    }
+                }
+            }
+
+            match tx.commit() {
+                Ok(_) => {
+                    results.extend(batch_results);
+                },
+                Err(e) => {
+                    warn!("failed to commit the batch {}", e);
+                    for r in batch_results {
+                        results.push(r.and(Err(BarnError::TxCommitError)));
                     }
                 }
             }
         }
 
-        let send_result = sn.send(Ok(Bytes::from_static(b"]")));
-        drop(sn);
-        let _ = tx.commit();
+        Ok(results)
+    }
 
-        Ok(())
+    pub fn get(&self, id: u64, res_name: String) -> Result<Value, BarnError> {
+        self.get_versioned(id, res_name).map(|(val, _)| val)
     }
-}
 
-impl Index {
-    fn insert(&self, tx: &mut RwTransaction, k: &Value, v: u64) -> Result<(), BarnError> {
-        let mut put_result = Err(lmdb::Error::from_err_code(-1));
-        match self.val_type.as_str() {
-            "integer" => {
-                if let Some(i) = k.as_i64() {
-                    put_result = tx.put(self.db, &i.to_le_bytes(), &v.to_le_bytes(), self.flags);
-                }
-            },
-            "string" => {
-                if let Some(s) = k.as_str() {
-                    let mut key_data: Vec<u8>;
-                    let match_word = self.val_format.as_str();
-                    match  match_word {
-                        "date-time" => {
-                            key_data = schema::parse_datetime(s)?;
-                        },
-                        "date" => {
-                            let date_with_zero_time = format!("{} 00:00:00", s);
-                            let d = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S");
-                            if let Err(e) = d {
-                                warn!("{}", e);
-                                return Err(BarnError::InvalidAttributeValueError);
-                            }
-                            key_data = d.unwrap().timestamp_millis().to_le_bytes().to_vec();
-                        },
-                        _ => {
-                           key_data = s.trim().to_lowercase().into_bytes();
+    /// Looks up the resources with the given ids in a single transaction, in the same order as the ids,
+    /// None for the ids that are not found
+    pub fn get_many(&self, res_name: String, ids: &[String]) -> Result<Vec<(String, Option<Value>)>, BarnError> {
+        let barrel = self.barrels.get(res_name.as_str());
+        if let None = barrel {
+            return Err(BarnError::UnknownResourceName);
+        }
+
+        let barrel = barrel.unwrap();
+        let tx_result = self.env.begin_ro_txn();
+        match tx_result {
+            Ok(tx) => {
+                let mut values = vec!();
+                for id in ids {
+                    let get_result = barrel.resolve_id(&tx, id).and_then(|pk| barrel.get(pk, &tx));
+                    match get_result {
+                        Ok(val) => values.push((id.clone(), Some(val))),
+                        Err(BarnError::ResourceNotFoundError) => values.push((id.clone(), None)),
+                        Err(e) => {
+                            return Err(e);
                         }
                     }
-
-                    put_result = tx.put(self.db, AsRef::<Vec<u8>>::as_ref(&key_data), &v.to_le_bytes(), self.flags);
-                }
-            },
-            "number" => {
-                if let Some(f) = k.as_f64() {
-                    put_result = tx.put(self.db, &f.to_le_bytes(), &v.to_le_bytes(), self.flags);
                 }
+                let _ = tx.commit();
+                Ok(values)
             },
-            _ => {
-                return Err(BarnError::UnsupportedIndexValueType);
+            Err(e) => {
+                warn!("failed to begin transaction {}", e);
+                Err(BarnError::TxBeginError)
             }
         }
+    }
 
-        if let Err(e) = put_result {
-            return Err(BarnError::TxWriteError);
+    /// Returns the resource having the given value of an attribute with a unique index. The value is
+    /// encoded the same way as while indexing, string values are parsed for the numeric attributes.
+    pub fn get_by(&self, res_name: String, attr_path: &str, value: &Value) -> Result<Value, BarnError> {
+        let barrel = self.barrels.get(res_name.as_str());
+        if let None = barrel {
+            return Err(BarnError::UnknownResourceName);
         }
-        Ok(())
-    }
-}
 
-impl Barrel {
-    fn insert(&self, tx: &mut RwTransaction, data : &mut Value) -> Result<(), BarnError> {
-        let d_obj = data.as_object_mut();
-        if let None = d_obj {
-            return Err(BarnError::InvalidResourceDataError);
+        let tx_result = self.env.begin_ro_txn();
+        match tx_result {
+            Ok(tx) => {
+                let val_result = barrel.unwrap().get_by(&tx, attr_path, value);
+                let _ = tx.commit();
+                val_result
+            },
+            Err(e) => {
+                Err(BarnError::TxBeginError)
+            }
         }
+    }
 
-        let d_obj = d_obj.unwrap();
-        let pk_result = tx.get(self.db, &DB_PRIMARY_KEY_KEY);
-        let mut pk: u64 = 1;
-        if let Ok(r) = pk_result {
-            pk = u64::from_le_bytes(r.try_into().unwrap());
-            pk += 1;
+    /// Finds the PK of the resource from the value of its id attribute, which is the PK itself
+    /// unless the resource uses a different id strategy
+    pub fn resolve_id(&self, res_name: String, id: &str) -> Result<u64, BarnError> {
+        let barrel = self.barrels.get(res_name.as_str());
+        if let None = barrel {
+            return Err(BarnError::UnknownResourceName);
         }
 
-        let pk_val;
-        match self.id_attr_type.as_str() {
-            "string" => {
-                pk_val = Value::from(format!("{}", pk));
+        let tx_result = self.env.begin_ro_txn();
+        match tx_result {
+            Ok(tx) => {
+                let pk_result = barrel.unwrap().resolve_id(&tx, id);
+                let _ = tx.commit();
+                pk_result
             },
-            _ => {
-                pk_val = Value::from(pk);
+            Err(e) => {
+                Err(BarnError::TxBeginError)
             }
         }
-        let pk_existing_attr = d_obj.remove(&self.id_attr_name);
-        if let Some(id_val) = pk_existing_attr {
-            trace!("dropping the value {} given for ID attribute {}", &id_val, &self.id_attr_name);
+    }
+
+    pub fn id_attr_name(&self, res_name: &str) -> Option<&str> {
+        self.barrels.get(res_name).map(|b| b.id_attr_name.as_str())
+    }
+
+    /// Returns the resource along with its current version
+    pub fn get_versioned(&self, id: u64, res_name: String) -> Result<(Value, u64), BarnError> {
+        let barrel = self.barrels.get(res_name.as_str());
+        if let None = barrel {
+            return Err(BarnError::UnknownResourceName);
         }
 
-        d_obj.insert(self.id_attr_name.clone(), pk_val);
+        let tx_result = self.env.begin_ro_txn();
+        match tx_result {
+            Ok(tx) => {
+                let val_result = barrel.unwrap().get_versioned(id, &tx);
+                let _ = tx.commit();
+                val_result
+            },
+            Err(e) => {
+                Err(BarnError::TxBeginError)
+            }
+        }
+    }
 
-        let mut buf: Vec<u8> = Vec::new();
-        let ser_result = data.serialize(&mut Serializer::new(&mut buf));
-        match ser_result {
-            Ok(_) => {
-                // first update indices, this will catch any unique constraint violations
-                for (at_name, i) in &self.indices {
-                    let at = data.pointer(&i.at_path);
-                    if let Some(at_val) = at {
-                        i.insert(tx, at_val, pk)?;
-                    }
-                }
+    /// Replaces the resource if its current version satisfies the precondition, returns the new version
+    pub fn update(&self, res_name: String, id: u64, r: &mut Value, pre: &Precondition) -> Result<u64, BarnError> {
+        let barrel = self.barrels.get(res_name.as_str());
+        if let None = barrel {
+            return Err(BarnError::UnknownResourceName);
+        }
 
-                // then update the resource's DB
-                let put_result = tx.put(self.db, &pk.to_le_bytes(), AsRef::<Vec<u8>>::as_ref(&buf), self.flags);
-                if let Err(_) = put_result {
-                    return Err(BarnError::TxWriteError);
-                }
+        let tx_result = self.env.begin_rw_txn();
 
-                // store the updated PK value
-                let put_result = tx.put(self.db, &DB_PRIMARY_KEY_KEY, &pk.to_le_bytes(), PK_WRITE_FLAGS);
-                if let Err(e) = put_result {
-                    return Err(BarnError::TxWriteError);
+        match tx_result {
+            Ok(mut tx) => {
+                let barrel_result = barrel.unwrap().update(&mut tx, id, r, pre);
+                match barrel_result {
+                    Ok(version) => {
+                        match tx.commit() {
+                            Ok(_) => {
+                                Ok(version)
+                            },
+                            Err(e) => {
+                                warn!("failed to update resource {}", e);
+                                Err(BarnError::TxCommitError)
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        warn!("aborting transaction due to {}", e);
+                        tx.abort();
+                        Err(e)
+                    }
                 }
             },
             Err(e) => {
-                warn!("{:#?}", e);
-                return Err(BarnError::SerializationError);
+                warn!("failed to begin transaction {}", e);
+                Err(BarnError::TxBeginError)
             }
         }
-
-        Ok(())
     }
 
-    fn get(&self, id: u64, tx: &RoTransaction) -> Result<Value, BarnError> {
-        if id <= 0 {
-            debug!("invalid resource identifier {}", id);
-            return Err(BarnError::ResourceNotFoundError);
+    /// Updates the resource having the same value of the given attribute, which must have a unique index,
+    /// or inserts the resource if there is none
+    pub fn upsert(&self, res_name: String, unique_attr: &str, r: &mut Value) -> Result<Upserted, BarnError> {
+        let barrel = self.barrels.get(res_name.as_str());
+        if let None = barrel {
+            return Err(BarnError::UnknownResourceName);
         }
 
-        let get_result = tx.get(self.db, &id.to_le_bytes());
-        match get_result {
-            Err(e) => {
-                debug!("resource not found with identifier {}", id);
-                Err(BarnError::ResourceNotFoundError)
-            },
-            Ok(data) => {
-                let val_result = rmps::from_read(data);
-                match val_result {
-                    Ok(val) => {
-                        /*let d_obj = val.as_object_mut().unwrap();
-                        let id_val;
-                        match self.id_attr_type.as_str() {
-                            "string" => {
-                                id_val = Value::from(format!("{}", id));
+        let tx_result = self.env.begin_rw_txn();
+
+        match tx_result {
+            Ok(mut tx) => {
+                let barrel_result = barrel.unwrap().upsert(&mut tx, unique_attr, r);
+                match barrel_result {
+                    Ok(upserted) => {
+                        match tx.commit() {
+                            Ok(_) => {
+                                Ok(upserted)
                             },
-                            _ => {
-                                id_val = Value::from(id);
+                            Err(e) => {
+                                warn!("failed to upsert resource {}", e);
+                                Err(BarnError::TxCommitError)
                             }
                         }
-                        d_obj.insert(self.id_attr_name.clone(), id_val);*/
-                        Ok(val)
                     },
                     Err(e) => {
-                        warn!("failed to deserialize the resource with identifier {}", id);
-                        Err(BarnError::DeSerializationError)
+                        warn!("aborting transaction due to {}", e);
+                        tx.abort();
+                        Err(e)
                     }
                 }
+            },
+            Err(e) => {
+                warn!("failed to begin transaction {}", e);
+                Err(BarnError::TxBeginError)
             }
         }
     }
-}
+
+    /// Applies the given function on the stored resource and writes back the result, all within
+    /// a single transaction. The function is expected to perform the schema validation.
+    /// Returns the patched resource and its new version.
+    pub fn patch<F>(&self, res_name: String, id: u64, pre: &Precondition, apply: F) -> Result<(Value, u64), BarnError>
+    where F: FnOnce(&mut Value) -> Result<(), BarnError> {
+        let barrel = self.barrels.get(res_name.as_str());
+        if let None = barrel {
+            return Err(BarnError::UnknownResourceName);
+        }
+
+        let tx_result = self.env.begin_rw_txn();
+
+        match tx_result {
+            Ok(mut tx) => {
+                let barrel_result = barrel.unwrap().patch(&mut tx, id, pre, apply);
+                match barrel_result {
+                    Ok(val) => {
+                        match tx.commit() {
+                            Ok(_) => {
+                                Ok(val)
+                            },
+                            Err(e) => {
+                                warn!("failed to patch resource {}", e);
+                                Err(BarnError::TxCommitError)
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        warn!("aborting transaction due to {}", e);
+                        tx.abort();
+                        Err(e)
+                    }
+                }
+            },
+            Err(e) => {
+                warn!("failed to begin transaction {}", e);
+                Err(BarnError::TxBeginError)
+            }
+        }
+    }
+
+    pub fn delete(&self, res_name: String, id: u64, pre: &Precondition) -> Result<Value, BarnError> {
+        let barrel = self.barrels.get(res_name.as_str());
+        if let None = barrel {
+            return Err(BarnError::UnknownResourceName);
+        }
+
+        let tx_result = self.env.begin_rw_txn();
+
+        match tx_result {
+            Ok(mut tx) => {
+                let barrel_result = barrel.unwrap().delete(&mut tx, id, pre);
+                match barrel_result {
+                    Ok(val) => {
+                        match tx.commit() {
+                            Ok(_) => {
+                                Ok(val)
+                            },
+                            Err(e) => {
+                                warn!("failed to delete resource {}", e);
+                                Err(BarnError::TxCommitError)
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        warn!("aborting transaction due to {}", e);
+                        tx.abort();
+                        Err(e)
+                    }
+                }
+            },
+            Err(e) => {
+                warn!("failed to begin transaction {}", e);
+                Err(BarnError::TxBeginError)
+            }
+        }
+    }
+
+    /// Streams the matching resources as a JSON array. If the query has a limit and there are more
+    /// matching resources the position of the last resource sent is returned, which can be set as the
+    /// `after` value of the query to fetch the next page.
+    pub fn search(&self, res_name: String, q: Query, sn: Sender<Result<Bytes, std::io::Error>>) -> Result<Option<query::Cursor>, BarnError> {
+        let barrel = self.barrels.get(res_name.as_str());
+        if let None = barrel {
+            return Err(BarnError::UnknownResourceName);
+        }
+
+        let tx_result = self.env.begin_ro_txn();
+        if let Err(e) = tx_result {
+            return Err(BarnError::TxBeginError);
+        }
+
+        let barrel = barrel.unwrap();
+        let tx = tx_result.unwrap();
+
+        let send_result = sn.send(Ok(Bytes::from_static(b"[")));
+
+        let mut send_comma = false;
+        let mut to_skip = q.offset;
+        let mut sent: usize = 0;
+        let mut last_sent: Option<query::Cursor> = None;
+        let mut has_more = false;
+        // returns false when no further records should be processed
+        let send_match = |key: Option<&[u8]>, pk: u64, json_val: &Value| -> bool {
+            if to_skip > 0 {
+                to_skip -= 1;
+                return true;
+            }
+            // stop only after finding a match beyond the limit, this avoids handing out a cursor to an empty page
+            if Some(sent) == q.limit {
+                has_more = true;
+                return false;
+            }
+            let str_result = if q.fields.is_empty() {
+                serde_json::to_vec(json_val)
+            }
+            else {
+                serde_json::to_vec(&query::project(json_val, &q.fields))
+            };
+            match str_result {
+                Ok(vec) => {
+                    if send_comma {
+                        let send_result = sn.send(Ok(Bytes::from_static(b",")));
+                    }
+                    let send_result = sn.send(Ok(Bytes::from(vec)));
+                    if let Err(e) = send_result {
+                        warn!("error received while sending search results {:?}", e);
+                        return false;
+                    }
+                    send_comma = true;
+                    sent += 1;
+                    last_sent = Some(query::Cursor { key: key.map(|k| k.to_vec()), pk });
+                }
+                Err(e) => {
+                    warn!("failed to convert the result to string, stopping further processing {:?}", e);
+                    return false;
+                }
+            }
+            true
+        };
+
+        let match_result = barrel.find_matches(&tx, &q, send_match);
+
+        let send_result = sn.send(Ok(Bytes::from_static(b"]")));
+        drop(sn);
+        let _ = tx.commit();
+
+        match_result?;
+        if has_more {
+            return Ok(last_sent);
+        }
+        Ok(None)
+    }
+
+    /// Aggregates the resources matching the query, limit, offset, sort and fields of the query are ignored.
+    /// Returns an object with the count and the aggregated value, or an array of such objects with
+    /// an additional `group` attribute when grouping.
+    pub fn aggregate(&self, res_name: String, q: Query, agg: Aggregate) -> Result<Value, BarnError> {
+        let barrel = self.barrels.get(res_name.as_str());
+        if let None = barrel {
+            return Err(BarnError::UnknownResourceName);
+        }
+
+        if agg.op != AggregateOp::Count && agg.at_path.is_none() {
+            warn!("attribute to be aggregated is missing for {}", agg.op.name());
+            return Err(BarnError::BadSearchFilter);
+        }
+
+        let tx_result = self.env.begin_ro_txn();
+        match tx_result {
+            Ok(tx) => {
+                let agg_result = barrel.unwrap().aggregate(&tx, &q, &agg);
+                let _ = tx.commit();
+                agg_result
+            },
+            Err(e) => {
+                warn!("failed to begin transaction {}", e);
+                Err(BarnError::TxBeginError)
+            }
+        }
+    }
+
+    /// Runs the given function in a single read-write transaction which is committed if the function
+    /// returns Ok and aborted otherwise, discarding all the changes made through the transaction.
+    pub fn transaction<F, T>(&self, f: F) -> Result<T, BarnError>
+    where F: FnOnce(&mut Txn) -> Result<T, BarnError> {
+        let tx_result = self.env.begin_rw_txn();
+        if let Err(e) = tx_result {
+            warn!("failed to begin transaction {}", e);
+            return Err(BarnError::TxBeginError);
+        }
+
+        let mut txn = Txn {
+            barrels: &self.barrels,
+            tx: tx_result.unwrap()
+        };
+
+        match f(&mut txn) {
+            Ok(val) => {
+                match txn.tx.commit() {
+                    Ok(_) => Ok(val),
+                    Err(e) => {
+                        warn!("failed to commit transaction {}", e);
+                        Err(BarnError::TxCommitError)
+                    }
+                }
+            },
+            Err(e) => {
+                warn!("aborting transaction due to {}", e);
+                txn.tx.abort();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'a> Txn<'a> {
+    fn barrel(&self, res_name: &str) -> Result<&'a Barrel, BarnError> {
+        match self.barrels.get(res_name) {
+            Some(barrel) => Ok(barrel),
+            None => Err(BarnError::UnknownResourceName)
+        }
+    }
+
+    /// Runs the write in a nested transaction, a failed write leaves no partial changes behind
+    /// even if the caller goes on and commits the transaction
+    fn nested<F, T>(&mut self, write: F) -> Result<T, BarnError>
+    where F: FnOnce(&mut RwTransaction) -> Result<T, BarnError> {
+        let nested_result = self.tx.begin_nested_txn();
+        if let Err(e) = nested_result {
+            warn!("failed to begin nested transaction {}", e);
+            return Err(BarnError::TxBeginError);
+        }
+
+        let mut nested = nested_result.unwrap();
+        match write(&mut nested) {
+            Ok(val) => {
+                match nested.commit() {
+                    Ok(_) => Ok(val),
+                    Err(e) => {
+                        warn!("failed to commit nested transaction {}", e);
+                        Err(BarnError::TxCommitError)
+                    }
+                }
+            },
+            Err(e) => {
+                nested.abort();
+                Err(e)
+            }
+        }
+    }
+
+    /// Inserts the resource and returns its PK
+    pub fn insert(&mut self, res_name: String, r: &mut Value) -> Result<u64, BarnError> {
+        let barrel = self.barrel(res_name.as_str())?;
+        self.nested(|tx| barrel.insert(tx, r))
+    }
+
+    pub fn get(&self, id: u64, res_name: String) -> Result<Value, BarnError> {
+        let barrel = self.barrel(res_name.as_str())?;
+        barrel.get(id, &self.tx)
+    }
+
+    pub fn resolve_id(&self, res_name: String, id: &str) -> Result<u64, BarnError> {
+        let barrel = self.barrel(res_name.as_str())?;
+        barrel.resolve_id(&self.tx, id)
+    }
+
+    pub fn get_versioned(&self, id: u64, res_name: String) -> Result<(Value, u64), BarnError> {
+        let barrel = self.barrel(res_name.as_str())?;
+        barrel.get_versioned(id, &self.tx)
+    }
+
+    pub fn update(&mut self, res_name: String, id: u64, r: &mut Value, pre: &Precondition) -> Result<u64, BarnError> {
+        let barrel = self.barrel(res_name.as_str())?;
+        self.nested(|tx| barrel.update(tx, id, r, pre))
+    }
+
+    pub fn upsert(&mut self, res_name: String, unique_attr: &str, r: &mut Value) -> Result<Upserted, BarnError> {
+        let barrel = self.barrel(res_name.as_str())?;
+        self.nested(|tx| barrel.upsert(tx, unique_attr, r))
+    }
+
+    pub fn patch<F>(&mut self, res_name: String, id: u64, pre: &Precondition, apply: F) -> Result<(Value, u64), BarnError>
+    where F: FnOnce(&mut Value) -> Result<(), BarnError> {
+        let barrel = self.barrel(res_name.as_str())?;
+        self.nested(|tx| barrel.patch(tx, id, pre, apply))
+    }
+
+    pub fn delete(&mut self, res_name: String, id: u64, pre: &Precondition) -> Result<Value, BarnError> {
+        let barrel = self.barrel(res_name.as_str())?;
+        self.nested(|tx| barrel.delete(tx, id, pre))
+    }
+}
+
+impl VersionMatch {
+    pub fn contains(&self, version: u64) -> bool {
+        match self {
+            VersionMatch::Any => true,
+            VersionMatch::Versions(versions) => versions.contains(&version)
+        }
+    }
+}
+
+impl Precondition {
+    /// A precondition satisfied only by the given version
+    pub fn version(version: u64) -> Precondition {
+        Precondition {
+            if_match: Some(VersionMatch::Versions(vec!(version))),
+            if_none_match: None
+        }
+    }
+
+    /// Fails with PreconditionFailed if the given current version is not acceptable
+    pub fn check(&self, current: u64) -> Result<(), BarnError> {
+        let matched = self.if_match.as_ref().is_none_or(|m| m.contains(current));
+        let none_matched = self.if_none_match.as_ref().is_none_or(|m| !m.contains(current));
+        if matched && none_matched {
+            return Ok(());
+        }
+
+        debug!("precondition {:?} failed for the version {}", self, current);
+        Err(BarnError::PreconditionFailed(current))
+    }
+}
+
+impl Index {
+    /// The attribute path of a single attribute index, None for compound indices
+    fn at_path(&self) -> Option<&str> {
+        if self.parts.len() == 1 {
+            return Some(self.parts[0].at_path.as_str());
+        }
+        None
+    }
+
+    /// The comma separated attribute paths of this index, used when reporting errors
+    fn attr_paths(&self) -> String {
+        self.parts.iter().map(|p| p.at_path.as_str()).collect::<Vec<&str>>().join(",")
+    }
+
+    /// Returns the values of all the indexed attributes of the resource, None if any of them is missing
+    /// unless the missing attributes are indexed as null
+    fn values_of<'r>(&self, r: &'r Value) -> Option<Vec<&'r Value>> {
+        if self.index_missing {
+            return Some(self.parts.iter().map(|p| r.pointer(&p.at_path).unwrap_or(&NULL)).collect());
+        }
+        self.parts.iter().map(|p| r.pointer(&p.at_path)).collect()
+    }
+
+    /// Returns the values and the key of each entry of the resource in this index. Array attributes
+    /// produce an entry per element, none are produced if any of the attributes is missing.
+    /// Unique indices leave out the entries with a null value, nulls do not conflict with each other.
+    fn entries_of<'r>(&self, r: &'r Value) -> Result<Vec<IndexEntry<'r>>, BarnError> {
+        let values = match self.values_of(r) {
+            Some(values) => values,
+            None => return Ok(vec!())
+        };
+
+        let mut tuples: Vec<Vec<&Value>> = vec!(vec!());
+        for (p, v) in self.parts.iter().zip(values) {
+            let elements: Vec<&Value> = match v {
+                Value::Array(elements) if p.multi => elements.iter().collect(),
+                _ => vec!(v)
+            };
+            tuples = tuples.into_iter()
+                .flat_map(|t| elements.iter().map(move |e| {
+                    let mut t = t.clone();
+                    t.push(*e);
+                    t
+                }))
+                .collect();
+        }
+
+        let mut entries = vec!();
+        for t in tuples {
+            if self.unique && t.iter().any(|v| v.is_null()) {
+                continue;
+            }
+            let key_data = self.key_of(&t)?;
+            // repeated elements map to the same entry
+            if entries.iter().all(|(_, k)| *k != key_data) {
+                entries.push((t, key_data));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn key_of(&self, values: &[&Value]) -> Result<Vec<u8>, BarnError> {
+        if self.parts.len() == 1 {
+            return self.parts[0].to_key(values[0]);
+        }
+
+        let mut key_data = vec!();
+        for (p, v) in self.parts.iter().zip(values) {
+            key_data.extend(p.to_compound_key(v)?);
+        }
+        Ok(key_data)
+    }
+
+    /// Returns the value and the PK of the resource already holding it if a unique value is taken
+    fn insert(&self, tx: &mut RwTransaction, r: &Value, v: u64) -> Result<Option<(Value, u64)>, BarnError> {
+        for (values, key_data) in self.entries_of(r)? {
+            if let Some(conflict) = self.put(tx, values, &key_data, v)? {
+                return Ok(Some(conflict));
+            }
+        }
+        Ok(None)
+    }
+
+    fn put(&self, tx: &mut RwTransaction, values: Vec<&Value>, key_data: &[u8], v: u64) -> Result<Option<(Value, u64)>, BarnError> {
+        let put_result = tx.put(self.db, &key_data, &v.to_le_bytes(), self.flags);
+        match put_result {
+            Ok(_) => Ok(None),
+            Err(lmdb::Error::KeyExist) if self.unique => {
+                let value = match self.at_path() {
+                    Some(_) => values[0].clone(),
+                    None => Value::Array(values.into_iter().cloned().collect())
+                };
+                debug!("duplicate value {} for the unique index {}", &value, &self.name);
+                let existing_pk = match tx.get(self.db, &key_data) {
+                    Ok(pk) => u64::from_le_bytes(pk.try_into().unwrap()),
+                    Err(e) => {
+                        warn!("failed to read the existing index entry {}", e);
+                        return Err(BarnError::TxReadError);
+                    }
+                };
+                Ok(Some((value, existing_pk)))
+            },
+            Err(e) => {
+                warn!("failed to insert index entry {}", e);
+                Err(write_error(e))
+            }
+        }
+    }
+
+    /// Returns true if any of the conditions looks up null, which is also matched by
+    /// the resources missing the attribute but they are not present in this index.
+    /// Unique indices have no null entries at all.
+    fn misses_nulls(&self, conditions: &[Condition]) -> bool {
+        (self.unique || !self.index_missing) && conditions.iter().any(|c| c.value.is_null() && self.parts.iter().any(|p| p.at_path == c.at_path))
+    }
+
+    /// Ranks how selective this index is for the given conditions, 0 if it cannot be used.
+    /// Equality on a unique key ranks highest, followed by equality on each leading attribute and then ranges.
+    fn rank(&self, conditions: &[Condition]) -> u32 {
+        if self.misses_nulls(conditions) {
+            return 0;
+        }
+        let eq_len = self.parts.iter().take_while(|p| conditions.iter().any(|c| c.at_path == p.at_path && c.op == Op::Eq)).count();
+        if eq_len == self.parts.len() {
+            if self.unique {
+                return 100;
+            }
+            return 10 * eq_len as u32;
+        }
+
+        let mut rank = 10 * eq_len as u32;
+        if conditions.iter().any(|c| self.parts[eq_len].plans(c)) {
+            rank += 1;
+        }
+        rank
+    }
+
+    /// Computes the range of keys satisfying the conditions on this index's attributes along with
+    /// the attributes whose conditions are all covered by the range.
+    /// Returns None if there are no conditions with a value usable as a key.
+    fn bounds(&self, conditions: &[Condition]) -> Result<Option<(KeyRange, Vec<&str>)>, BarnError> {
+        if self.misses_nulls(conditions) {
+            return Ok(None);
+        }
+        if let Some(at_path) = self.at_path() {
+            let range = self.parts[0].bounds(conditions, None)?;
+            let mut served = vec!();
+            if self.parts[0].serves(conditions) {
+                served.push(at_path);
+            }
+            return Ok(range.map(|r| (r, served)));
+        }
+
+        // equality conditions on the leading attributes form a prefix shared by all the matching keys,
+        // the first attribute without one can be further restricted to a range
+        let mut prefix = vec!();
+        let mut served = vec!();
+        for p in &self.parts {
+            let on_part: Vec<&Condition> = conditions.iter().filter(|c| c.at_path == p.at_path).collect();
+            let eq_key = on_part.iter()
+                .filter(|c| c.op == Op::Eq)
+                .find_map(|c| p.to_compound_key(&c.value).ok());
+            match eq_key {
+                Some(k) => {
+                    prefix.extend(k);
+                    if on_part.len() == 1 {
+                        served.push(p.at_path.as_str());
+                    }
+                },
+                None => {
+                    let range = p.bounds(conditions, Some(&prefix))?;
+                    if range.is_some() {
+                        if p.serves(conditions) {
+                            served.push(p.at_path.as_str());
+                        }
+                        return Ok(range.map(|r| (r, served)));
+                    }
+                    if prefix.is_empty() {
+                        return Ok(None);
+                    }
+                    return Ok(Some(((Bound::Included(prefix.clone()), prefix_end(&prefix)), served)));
+                }
+            }
+        }
+
+        Ok(Some(((Bound::Included(prefix.clone()), prefix_end(&prefix)), served)))
+    }
+
+    /// Returns the number of resources stored under each key of the index in key order.
+    /// Returns None if the keys of this index cannot be decoded into attribute values.
+    fn cardinalities<T: Transaction>(&self, tx: &T) -> Result<Option<Vec<(Value, u64)>>, BarnError> {
+        if self.at_path().is_none() || self.parts[0].multi {
+            return Ok(None);
+        }
+
+        let mut counts: Vec<(Vec<u8>, u64)> = vec!();
+        let cursor = tx.open_ro_cursor(self.db);
+        if let Err(e) = cursor {
+            warn!("failed to open cursor on index {}", e);
+            return Err(BarnError::TxReadError);
+        }
+
+        for row in cursor.unwrap().iter_start() {
+            if let Err(e) = row {
+                warn!("failed to read index entry {}", e);
+                return Err(BarnError::TxReadError);
+            }
+
+            let (key, _) = row.unwrap();
+            match counts.last_mut() {
+                Some((k, count)) if k.as_slice() == key => *count += 1,
+                _ => counts.push((key.to_vec(), 1))
+            }
+        }
+
+        let mut cardinalities = vec!();
+        for (k, count) in counts {
+            match self.parts[0].decode_key(&k) {
+                Some(v) => cardinalities.push((v, count)),
+                None => return Ok(None)
+            }
+        }
+        Ok(Some(cardinalities))
+    }
+
+    /// Walks the index in key order between the given bounds and returns the keys and PKs,
+    /// resources having multiple entries in the range are returned only at the first one
+    /// Visits the entries in the range in the order of their keys and PKs, or in the reverse order, resuming
+    /// past the given entry. Resources are visited once even if several elements of their arrays are in the range.
+    /// Stops when the visitor returns false.
+    fn walk<T: Transaction, F>(&self, tx: &T, range: &KeyRange, descending: bool, after: Option<(&[u8], u64)>, mut visit: F) -> Result<(), BarnError>
+    where F: FnMut(&[u8], u64) -> bool {
+        let cursor = tx.open_ro_cursor(self.db);
+        if let Err(e) = cursor {
+            warn!("failed to open cursor on index {}", e);
+            return Err(BarnError::TxReadError);
+        }
+
+        let cursor = cursor.unwrap();
+        let (lower, upper) = range;
+        let multi = self.parts.iter().any(|p| p.multi);
+        // the resources visited before the given entry are tracked from the start of the range
+        // to skip their other elements
+        let seek_after = if multi { None } else { after };
+        let mut entry = match (descending, seek_after) {
+            (false, Some(a)) => seek(&cursor, a.0, |e| e > a)?,
+            (false, None) => match lower {
+                Bound::Included(k) => seek(&cursor, k, |_| true)?,
+                Bound::Excluded(k) => seek(&cursor, k, |(key, _)| key > k.as_slice())?,
+                Bound::Unbounded => read_entry(cursor.get(None, None, MDB_FIRST))?
+            },
+            (true, Some(a)) => {
+                let at = seek(&cursor, a.0, |e| e >= a)?;
+                before(&cursor, at)?
+            },
+            (true, None) => match upper {
+                Bound::Included(k) => {
+                    let at = seek(&cursor, k, |(key, _)| key > k.as_slice())?;
+                    before(&cursor, at)?
+                },
+                Bound::Excluded(k) => {
+                    let at = seek(&cursor, k, |_| true)?;
+                    before(&cursor, at)?
+                },
+                Bound::Unbounded => read_entry(cursor.get(None, None, MDB_LAST))?
+            }
+        };
+
+        let mut seen = HashSet::new();
+        while let Some((key, pk)) = entry {
+            let in_range = if descending {
+                match lower {
+                    Bound::Included(k) => key >= k.as_slice(),
+                    Bound::Excluded(k) => key > k.as_slice(),
+                    Bound::Unbounded => true
+                }
+            }
+            else {
+                match upper {
+                    Bound::Included(k) => key <= k.as_slice(),
+                    Bound::Excluded(k) => key < k.as_slice(),
+                    Bound::Unbounded => true
+                }
+            };
+            if !in_range {
+                break;
+            }
+
+            let resumed = after.is_none_or(|a| if descending { (key, pk) < a } else { (key, pk) > a });
+            if (!multi || seen.insert(pk)) && resumed && !visit(key, pk) {
+                break;
+            }
+            entry = read_entry(cursor.get(None, None, if descending { MDB_PREV } else { MDB_NEXT }))?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, tx: &mut RwTransaction, r: &Value, v: u64) -> Result<(), BarnError> {
+        for (_, key_data) in self.entries_of(r)? {
+            self.del(tx, &key_data, v)?;
+        }
+        Ok(())
+    }
+
+    fn del(&self, tx: &mut RwTransaction, key_data: &[u8], v: u64) -> Result<(), BarnError> {
+        let pk = v.to_le_bytes();
+        let mut data: Option<&[u8]> = None;
+        // non-unique indices hold multiple PKs under the same key, remove only the one belonging to this resource
+        if !self.unique {
+            data = Some(&pk);
+        }
+
+        let del_result = tx.del(self.db, &key_data, data);
+        match del_result {
+            Ok(_) => Ok(()),
+            Err(lmdb::Error::NotFound) => {
+                debug!("no index entry found at {} for the resource with identifier {}", self.attr_paths(), v);
+                Ok(())
+            },
+            Err(e) => {
+                warn!("failed to delete index entry {}", e);
+                Err(BarnError::TxWriteError)
+            }
+        }
+    }
+
+    /// Returns the PK of the resource having the given value, only for unique single attribute indices
+    fn find_unique<T: Transaction>(&self, tx: &T, k: &Value) -> Result<Option<u64>, BarnError> {
+        let key_data = self.key_of(&[k])?;
+        match tx.get(self.db, &key_data) {
+            Ok(pk) => Ok(Some(u64::from_le_bytes(pk.try_into().unwrap()))),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => {
+                warn!("failed to read the index entry {}", e);
+                Err(BarnError::TxReadError)
+            }
+        }
+    }
+}
+
+impl KeyPart {
+    /// Computes the range of keys satisfying the conditions on this attribute. For compound indices
+    /// the prefix is made of the keys of the preceding attributes and all the keys in the range start with it.
+    /// Returns None if there are no conditions with a value usable as a key.
+    fn bounds(&self, conditions: &[Condition], compound_prefix: Option<&[u8]>) -> Result<Option<KeyRange>, BarnError> {
+        let mut conditions: Vec<&Condition> = conditions.iter().filter(|c| self.plans(c)).collect();
+        if self.multi {
+            // each condition on an array attribute may be satisfied by a different element,
+            // the range of a single condition is used and the others are checked on the resources
+            conditions.sort_by_key(|c| c.op != Op::Eq);
+            conditions.truncate(1);
+        }
+
+        let compound = compound_prefix.is_some();
+        let prefix = compound_prefix.unwrap_or(&[]);
+        let mut lower: Bound<Vec<u8>> = Bound::Unbounded;
+        let mut upper: Bound<Vec<u8>> = Bound::Unbounded;
+        let mut bounded = false;
+        for c in conditions {
+            if c.value.is_null() && c.op != Op::Eq {
+                debug!("null cannot be used as a bound of a range on {}", &self.at_path);
+                continue;
+            }
+            let key_result = if c.op == Op::StartsWith {
+                self.to_prefix_key(&c.value, compound)
+            }
+            else if compound {
+                self.to_compound_key(&c.value)
+            }
+            else {
+                self.to_key(&c.value)
+            };
+            let key = match key_result {
+                Ok(k) => k,
+                Err(BarnError::InvalidAttributeValueError) => {
+                    debug!("value {} cannot be used to lookup index at {}", &c.value, &self.at_path);
+                    continue;
+                },
+                Err(e) => {
+                    return Err(e);
+                }
+            };
+            bounded = true;
+            if !compound {
+                match c.op {
+                    Op::Eq => {
+                        lower = max_lower(lower, Bound::Included(key.clone()));
+                        upper = min_upper(upper, Bound::Included(key));
+                    },
+                    Op::Gt => lower = max_lower(lower, Bound::Excluded(key)),
+                    Op::Ge => lower = max_lower(lower, Bound::Included(key)),
+                    // the key of null sorts before all the values and is not part of any range
+                    Op::Lt => {
+                        lower = max_lower(lower, Bound::Excluded(NULL_KEY.to_vec()));
+                        upper = min_upper(upper, Bound::Excluded(key));
+                    },
+                    Op::Le => {
+                        lower = max_lower(lower, Bound::Excluded(NULL_KEY.to_vec()));
+                        upper = min_upper(upper, Bound::Included(key));
+                    },
+                    Op::StartsWith => {
+                        // an empty prefix matches all the keys other than the key of null
+                        if key.is_empty() {
+                            lower = max_lower(lower, Bound::Excluded(NULL_KEY.to_vec()));
+                        }
+                        else {
+                            upper = min_upper(upper, prefix_end(&key));
+                            lower = max_lower(lower, Bound::Included(key));
+                        }
+                    }
+                }
+                continue;
+            }
+
+            // keys of compound indices continue after this attribute, so the bounds are
+            // placed right before or right after all the keys starting with the value
+            let mut key_data = prefix.to_vec();
+            key_data.extend(key);
+            let after = match successor(&key_data) {
+                Some(s) => Bound::Excluded(s),
+                None => Bound::Unbounded
+            };
+            match c.op {
+                Op::Eq | Op::StartsWith => {
+                    lower = max_lower(lower, Bound::Included(key_data));
+                    upper = min_upper(upper, after);
+                },
+                Op::Gt => {
+                    lower = match after {
+                        Bound::Excluded(s) => max_lower(lower, Bound::Included(s)),
+                        // nothing sorts after the value, leave an empty range
+                        _ => {
+                            upper = Bound::Included(key_data.clone());
+                            Bound::Excluded(key_data)
+                        }
+                    };
+                },
+                Op::Ge => lower = max_lower(lower, Bound::Included(key_data)),
+                // values are marked with a one byte, which leaves out the nulls marked with a zero byte
+                Op::Lt => {
+                    lower = max_lower(lower, Bound::Included([prefix, &[1]].concat()));
+                    upper = min_upper(upper, Bound::Excluded(key_data));
+                },
+                Op::Le => {
+                    lower = max_lower(lower, Bound::Included([prefix, &[1]].concat()));
+                    upper = min_upper(upper, after);
+                }
+            }
+        }
+
+        if !bounded {
+            return Ok(None);
+        }
+        if !prefix.is_empty() {
+            lower = max_lower(lower, Bound::Included(prefix.to_vec()));
+            upper = min_upper(upper, prefix_end(prefix));
+        }
+        Ok(Some((lower, upper)))
+    }
+
+    /// Returns true if the range computed from the conditions on this attribute satisfies all of them
+    fn serves(&self, conditions: &[Condition]) -> bool {
+        let on_part: Vec<&Condition> = conditions.iter().filter(|c| c.at_path == self.at_path).collect();
+        if !on_part.iter().all(|c| self.plans(c)) {
+            return false;
+        }
+        !self.multi || on_part.len() == 1
+    }
+
+    /// Evaluates the condition on the resource. Equality and prefixes on strings compare the values
+    /// as normalized by the index and dates compare as timestamps, so that the resources match
+    /// the same values whether or not the index is used for the lookup.
+    fn matches(&self, c: &Condition, r: &Value) -> bool {
+        let values: Vec<&Value> = match r.pointer(&c.at_path) {
+            Some(Value::Array(elements)) => elements.iter().collect(),
+            Some(v) => vec!(v),
+            None => vec!()
+        };
+        match (&c.op, &c.value) {
+            (Op::StartsWith, Value::String(p)) if self.is_text() => {
+                let prefix = self.normalizer.normalize_prefix(p);
+                values.iter().any(|v| v.as_str().is_some_and(|s| self.normalizer.normalize(s).starts_with(&prefix)))
+            },
+            (op, Value::String(_)) if self.val_type == "string" && (*op == Op::Eq || (!self.is_text() && *op != Op::StartsWith)) => {
+                match self.to_key(&c.value) {
+                    Ok(key_data) => values.iter().any(|v| self.to_key(v).is_ok_and(|k| op.accepts(k.cmp(&key_data)))),
+                    Err(_) => c.matches(r)
+                }
+            },
+            _ => c.matches(r)
+        }
+    }
+
+    /// Returns true if the condition is on this attribute and can be looked up in the index.
+    /// Keys of text are normalized, which does not preserve the order of the values, so only equality
+    /// and prefixes are looked up for text and its ranges are checked on the resources. Dates are
+    /// keyed by their timestamps and support ranges.
+    fn plans(&self, c: &Condition) -> bool {
+        if c.at_path != self.at_path {
+            return false;
+        }
+        match c.op {
+            Op::Eq => true,
+            Op::StartsWith => self.is_text(),
+            _ => !self.is_text()
+        }
+    }
+
+    /// Returns true if the keys are the normalized bytes of the string values
+    fn is_text(&self) -> bool {
+        self.val_type == "string" && self.val_format != "date" && self.val_format != "date-time"
+    }
+
+    /// Converts the key back to the attribute value, strings are returned in their normalized form.
+    /// Returns None for dates and keys that cannot be decoded.
+    fn decode_key(&self, key: &[u8]) -> Option<Value> {
+        if key == NULL_KEY {
+            return Some(Value::Null);
+        }
+        match (self.val_type.as_str(), self.val_format.as_str()) {
+            ("boolean", _) => match key {
+                [1] => Some(Value::Bool(false)),
+                [2] => Some(Value::Bool(true)),
+                _ => None
+            },
+            ("integer", _) => schema::key_to_i64(key).map(Value::from),
+            ("number", _) => schema::key_to_f64(key).map(Value::from),
+            ("string", "date") | ("string", "date-time") => None,
+            ("string", _) => {
+                let mut key_data = key.to_vec();
+                if key_data.starts_with(&[0, 0xFF]) {
+                    key_data.remove(1);
+                }
+                String::from_utf8(key_data).ok().map(Value::from)
+            },
+            _ => None
+        }
+    }
+
+    /// Converts a string value to the type of the indexed attribute, useful for the values taken from URLs
+    fn coerce(&self, k: &Value) -> Value {
+        if let Value::String(s) = k {
+            let parsed = match self.val_type.as_str() {
+                "integer" => s.trim().parse::<i64>().ok().map(Value::from),
+                "number" => s.trim().parse::<f64>().ok().map(Value::from),
+                "boolean" => s.trim().parse::<bool>().ok().map(Value::from),
+                _ => None
+            };
+            if let Some(v) = parsed {
+                return v;
+            }
+        }
+        k.clone()
+    }
+
+    /// Encodes the value as a component of a compound key. Values are marked with a leading one byte
+    /// and nulls with a zero byte. Numbers, dates and booleans have a fixed width, strings are terminated
+    /// with two zero bytes (zero bytes within are escaped) so that the concatenated keys sort in the order
+    /// of the value tuples.
+    fn to_compound_key(&self, k: &Value) -> Result<Vec<u8>, BarnError> {
+        if k.is_null() {
+            return Ok(NULL_KEY.to_vec());
+        }
+
+        if let (true, Some(s)) = (self.is_text(), k.as_str()) {
+            let mut key_data = compound_text_key(&self.normalizer.normalize(s));
+            key_data.extend([0, 0]);
+            return Ok(key_data);
+        }
+
+        let mut key_data = vec!(1);
+        key_data.extend(self.to_key(k)?);
+        Ok(key_data)
+    }
+
+    /// Encodes the value as the prefix of the keys of all the strings starting with it,
+    /// only string attributes other than dates support prefix lookups
+    fn to_prefix_key(&self, k: &Value, compound: bool) -> Result<Vec<u8>, BarnError> {
+        if !self.is_text() || !k.is_string() {
+            debug!("prefix {} cannot be looked up at {} of type {}", k, &self.at_path, &self.val_type);
+            return Err(BarnError::InvalidAttributeValueError);
+        }
+
+        let prefix = self.normalizer.normalize_prefix(k.as_str().unwrap());
+        if !compound {
+            return Ok(text_key(&prefix));
+        }
+        // without the terminator the key matches the longer strings too
+        Ok(compound_text_key(&prefix))
+    }
+
+    fn to_key(&self, k: &Value) -> Result<Vec<u8>, BarnError> {
+        if k.is_null() {
+            return Ok(NULL_KEY.to_vec());
+        }
+
+        match self.val_type.as_str() {
+            "boolean" => {
+                if let Some(b) = k.as_bool() {
+                    return Ok(vec!(if b { 2 } else { 1 }));
+                }
+            },
+            "integer" => {
+                if let Some(i) = k.as_i64() {
+                    return Ok(schema::i64_to_key(i));
+                }
+            },
+            "string" => {
+                if let Some(s) = k.as_str() {
+                    let match_word = self.val_format.as_str();
+                    let key_data = match  match_word {
+                        "date-time" => schema::parse_datetime(s)?,
+                        "date" => {
+                            let date_with_zero_time = format!("{} 00:00:00", s);
+                            let d = NaiveDateTime::parse_from_str(&date_with_zero_time, "%Y-%m-%d %H:%M:%S");
+                            if let Err(e) = d {
+                                warn!("{}", e);
+                                return Err(BarnError::InvalidAttributeValueError);
+                            }
+                            schema::i64_to_key(d.unwrap().timestamp_millis())
+                        },
+                        _ => text_key(&self.normalizer.normalize(s))
+                    };
+
+                    return Ok(key_data);
+                }
+            },
+            "number" => {
+                if let Some(f) = k.as_f64() {
+                    return Ok(schema::f64_to_key(f));
+                }
+            },
+            _ => {
+                return Err(BarnError::UnsupportedIndexValueType);
+            }
+        }
+
+        debug!("value {} at {} does not match the index type {}", k, &self.at_path, &self.val_type);
+        Err(BarnError::InvalidAttributeValueError)
+    }
+}
+
+impl Barrel {
+    fn insert(&self, tx: &mut RwTransaction, data : &mut Value) -> Result<u64, BarnError> {
+        let d_obj = data.as_object_mut();
+        if let None = d_obj {
+            return Err(BarnError::InvalidResourceDataError);
+        }
+
+        let d_obj = d_obj.unwrap();
+        let pk_result = tx.get(self.db, &DB_PRIMARY_KEY_KEY);
+        let mut pk: u64 = 1;
+        if let Ok(r) = pk_result {
+            pk = u64::from_le_bytes(r.try_into().unwrap());
+            pk += 1;
+        }
+
+        let pk_existing_attr = d_obj.remove(&self.id_attr_name);
+        let id_val = match self.id_strategy {
+            IdStrategy::Sequence => self.pk_value(pk),
+            IdStrategy::Client => {
+                match &pk_existing_attr {
+                    Some(v) if ext_id_key(v).is_some() => v.clone(),
+                    _ => {
+                        debug!("missing or invalid value given for ID attribute {}", &self.id_attr_name);
+                        return Err(BarnError::InvalidIdError);
+                    }
+                }
+            },
+            IdStrategy::UuidV4 => Value::from(Uuid::new_v4().to_string()),
+            IdStrategy::UuidV7 => Value::from(Uuid::now_v7().to_string()),
+            IdStrategy::Ulid => Value::from(Ulid::new().to_string())
+        };
+        if self.id_strategy != IdStrategy::Client {
+            if let Some(v) = pk_existing_attr {
+                trace!("dropping the value {} given for ID attribute {}", &v, &self.id_attr_name);
+            }
+        }
+
+        if let Some(ids_db) = self.ids_db {
+            let key = ext_id_key(&id_val).unwrap();
+            let put_result = tx.put(ids_db, &key, &pk.to_le_bytes(), WriteFlags::NO_OVERWRITE);
+            match put_result {
+                Ok(_) => {},
+                Err(lmdb::Error::KeyExist) => {
+                    debug!("duplicate value {} for ID attribute {}", &id_val, &self.id_attr_name);
+                    return Err(BarnError::UniqueConstraintViolation {
+                        index: self.id_attr_name.clone(),
+                        attr_path: format!("/{}", &self.id_attr_name),
+                        value: Box::new(id_val.clone()),
+                        existing_id: Box::new(id_val)
+                    });
+                },
+                Err(e) => {
+                    warn!("failed to map the ID {} {}", &id_val, e);
+                    return Err(write_error(e));
+                }
+            }
+        }
+
+        d_obj.insert(self.id_attr_name.clone(), id_val);
+        self.stamp(d_obj, None, FIRST_VERSION);
+
+        let ser_result = encode_record(FIRST_VERSION, data);
+        match ser_result {
+            Ok(buf) => {
+                // first update indices, this will catch any unique constraint violations
+                for (at_name, i) in &self.indices {
+                    self.index(tx, i, data, pk)?;
+                }
+
+                // then update the resource's DB
+                let put_result = tx.put(self.db, &pk.to_le_bytes(), AsRef::<Vec<u8>>::as_ref(&buf), self.flags);
+                if let Err(e) = put_result {
+                    warn!("failed to insert the resource {}", e);
+                    return Err(write_error(e));
+                }
+
+                // store the updated PK value
+                let put_result = tx.put(self.db, &DB_PRIMARY_KEY_KEY, &pk.to_le_bytes(), PK_WRITE_FLAGS);
+                if let Err(e) = put_result {
+                    warn!("failed to store the PK value {}", e);
+                    return Err(write_error(e));
+                }
+            },
+            Err(e) => {
+                return Err(e);
+            }
+        }
+
+        Ok(pk)
+    }
+
+    /// Returns the new version of the resource
+    fn update(&self, tx: &mut RwTransaction, id: u64, data: &mut Value, pre: &Precondition) -> Result<u64, BarnError> {
+        let (existing, version) = self.get_versioned(id, tx)?;
+        pre.check(version)?;
+        self.replace(tx, id, &existing, data, version + 1)?;
+        Ok(version + 1)
+    }
+
+    fn upsert(&self, tx: &mut RwTransaction, unique_attr: &str, data: &mut Value) -> Result<Upserted, BarnError> {
+        let index = self.unique_index(unique_attr)?;
+        let existing_pk = match data.pointer(index.at_path().unwrap()) {
+            Some(at_val) => index.find_unique(tx, at_val)?,
+            None => {
+                debug!("missing value of the attribute {} to upsert", unique_attr);
+                return Err(BarnError::InvalidResourceDataError);
+            }
+        };
+
+        match existing_pk {
+            Some(pk) => {
+                let version = self.update(tx, pk, data, &Precondition::default())?;
+                Ok(Upserted { pk, version, created: false })
+            },
+            None => {
+                let pk = self.insert(tx, data)?;
+                Ok(Upserted { pk, version: FIRST_VERSION, created: true })
+            }
+        }
+    }
+
+    fn get_by<T: Transaction>(&self, tx: &T, attr: &str, value: &Value) -> Result<Value, BarnError> {
+        let index = self.unique_index(attr)?;
+        let pk = index.find_unique(tx, &index.parts[0].coerce(value))?;
+        match pk {
+            Some(pk) => self.get(pk, tx),
+            None => {
+                debug!("resource not found with {} {}", attr, value);
+                Err(BarnError::ResourceNotFoundError)
+            }
+        }
+    }
+
+    /// Returns the part covering the attribute in the index with the smallest name, whose key encoding
+    /// is used for evaluating the conditions on the attribute
+    fn key_part(&self, at_path: &str) -> Option<&KeyPart> {
+        self.indices.values()
+            .flat_map(|i| i.parts.iter().map(move |p| (i.name.as_str(), p)))
+            .filter(|(_, p)| p.at_path == at_path)
+            .min_by_key(|(name, _)| *name)
+            .map(|(_, p)| p)
+    }
+
+    fn unique_index(&self, attr: &str) -> Result<&Index, BarnError> {
+        let at_path = query::to_pointer(attr);
+        let index = self.indices.values().find(|i| i.unique && i.at_path() == Some(at_path.as_str()) && !i.parts[0].multi);
+        match index {
+            Some(i) => Ok(i),
+            None => {
+                debug!("no unique index found on the attribute {}", attr);
+                Err(BarnError::NoUniqueIndexError(String::from(attr)))
+            }
+        }
+    }
+
+    fn patch<F>(&self, tx: &mut RwTransaction, id: u64, pre: &Precondition, apply: F) -> Result<(Value, u64), BarnError>
+    where F: FnOnce(&mut Value) -> Result<(), BarnError> {
+        let (existing, version) = self.get_versioned(id, tx)?;
+        pre.check(version)?;
+        let mut data = existing.clone();
+        apply(&mut data)?;
+        self.replace(tx, id, &existing, &mut data, version + 1)?;
+        Ok((data, version + 1))
+    }
+
+    fn replace(&self, tx: &mut RwTransaction, id: u64, existing: &Value, data: &mut Value, version: u64) -> Result<(), BarnError> {
+        let d_obj = data.as_object_mut();
+        if let None = d_obj {
+            return Err(BarnError::InvalidResourceDataError);
+        }
+
+        // the identifier in the path always wins over the one present in the body
+        let d_obj = d_obj.unwrap();
+        let id_val = match self.id_strategy {
+            IdStrategy::Sequence => self.pk_value(id),
+            _ => existing.get(&self.id_attr_name).cloned().unwrap_or(Value::Null)
+        };
+        d_obj.insert(self.id_attr_name.clone(), id_val);
+        self.stamp(d_obj, Some(existing), version);
+
+        let buf = encode_record(version, data)?;
+
+        // only the indices whose attribute values have changed need to be touched
+        for i in self.indices.values() {
+            if i.values_of(existing) == i.values_of(data) {
+                continue;
+            }
+
+            i.delete(tx, existing, id)?;
+            self.index(tx, i, data, id)?;
+        }
+
+        let put_result = tx.put(self.db, &id.to_le_bytes(), AsRef::<Vec<u8>>::as_ref(&buf), WriteFlags::empty());
+        if let Err(e) = put_result {
+            warn!("failed to update the resource with identifier {} {}", id, e);
+            return Err(write_error(e));
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, tx: &mut RwTransaction, id: u64, pre: &Precondition) -> Result<Value, BarnError> {
+        let (existing, version) = self.get_versioned(id, tx)?;
+        pre.check(version)?;
+
+        for i in self.indices.values() {
+            i.delete(tx, &existing, id)?;
+        }
+
+        let del_result = tx.del(self.db, &id.to_le_bytes(), None);
+        if let Err(e) = del_result {
+            warn!("failed to delete the resource with identifier {} {}", id, e);
+            return Err(BarnError::TxWriteError);
+        }
+
+        if let Some(ids_db) = self.ids_db {
+            if let Some(key) = existing.get(&self.id_attr_name).and_then(ext_id_key) {
+                let del_result = tx.del(ids_db, &key, None);
+                if let Err(e) = del_result {
+                    warn!("failed to delete the ID mapping of the resource with identifier {} {}", id, e);
+                    return Err(BarnError::TxWriteError);
+                }
+            }
+        }
+
+        Ok(existing)
+    }
+
+    /// Calls the given function with each resource matching the query until it returns false.
+    /// The resources are visited in the order of the sort attribute if present, otherwise in
+    /// the order of the index used for finding the candidates or in the order of PKs.
+    /// The function also receives the index key the resource was found under, None when visited in PK order.
+    /// Pages resume by seeking the index to the key and PK of the last resource of the previous page.
+    fn find_matches<T: Transaction, F>(&self, tx: &T, q: &Query, mut f: F) -> Result<(), BarnError>
+    where F: FnMut(Option<&[u8]>, u64, &Value) -> bool {
+        // reject the explicit conditions whose values cannot be used with the index of the attribute
+        for c in &q.conditions {
+            if c.value.is_null() && c.op != Op::Eq {
+                debug!("null cannot be compared using {:?} at {}", &c.op, &c.at_path);
+                return Err(BarnError::InvalidAttributeValueError);
+            }
+            if c.op == Op::StartsWith {
+                if !c.value.is_string() {
+                    debug!("prefix {} at {} is not a string", &c.value, &c.at_path);
+                    return Err(BarnError::InvalidAttributeValueError);
+                }
+                continue;
+            }
+            for p in self.indices.values().flat_map(|i| &i.parts) {
+                if p.at_path == c.at_path {
+                    p.to_key(&c.value)?;
+                }
+            }
+        }
+
+        let mut compiled_path = q.filter.as_ref().map(|expr| jsonpath_lib::compile(expr.as_str()));
+        let mut conditions = q.conditions.clone();
+        if let Some(expr) = &q.filter {
+            conditions.extend(query::parse_conditions(expr.as_str()));
+        }
+
+        let candidates = self.find_candidates(tx, &conditions, q.sort.as_ref())?;
+
+        // the explicit conditions on the attributes used for finding the candidates are already satisfied
+        let mut remaining: Vec<&Condition> = q.conditions.iter().collect();
+        if let Some(c) = &candidates {
+            remaining.retain(|rc| !c.served.contains(&rc.at_path.as_str()));
+        }
+        let remaining: Vec<(&Condition, Option<&KeyPart>)> = remaining.into_iter().map(|c| (c, self.key_part(&c.at_path))).collect();
+
+        // returns false when no further records should be processed
+        let mut call_if_matches = |key: Option<&[u8]>, pk: u64, json_val: Value| -> bool {
+            if !remaining.iter().all(|(c, p)| p.map_or_else(|| c.matches(&json_val), |p| p.matches(c, &json_val))) {
+                return true;
+            }
+            let mut result = Ok(vec!(&json_val));
+            if let Some(p) = compiled_path.as_mut() {
+                result = p(&json_val);
+            }
+            if result.is_ok() {
+                if result.unwrap().len() == 0 {
+                    return true;
+                }
+                return f(key, pk, &json_val);
+            }
+            true
+        };
+
+        match candidates {
+            Some(c) => {
+                // the resources missing from the sort index are visited last, their cursors have no key
+                let after_missing = q.after.as_ref().filter(|a| a.key.is_none());
+                let mut more = true;
+                if after_missing.is_none() {
+                    let after = q.after.as_ref().and_then(|a| a.key.as_deref().map(|k| (k, a.pk)));
+                    c.index.walk(tx, &c.range, c.descending, after, |key, pk| {
+                        if c.selected.as_ref().is_some_and(|s| !s.contains(&pk)) {
+                            return true;
+                        }
+                        let get_result = tx.get(self.db, &pk.to_le_bytes());
+                        if let Err(e) = get_result {
+                            warn!("index points to a missing resource with identifier {} {}", pk, e);
+                            return true;
+                        }
+                        let (_, json_val) = decode_record(get_result.unwrap()).unwrap();
+                        more = call_if_matches(Some(key), pk, json_val);
+                        more
+                    })?;
+                }
+
+                if more && c.with_missing {
+                    let start = after_missing.map_or(1, |a| a.pk + 1);
+                    self.walk_resources(tx, start, |pk, data| {
+                        if c.selected.as_ref().is_some_and(|s| !s.contains(&pk)) {
+                            return true;
+                        }
+                        let (_, json_val) = decode_record(data).unwrap();
+                        let indexed = match c.index.entries_of(&json_val) {
+                            Ok(entries) => !entries.is_empty(),
+                            Err(_) => false
+                        };
+                        indexed || call_if_matches(None, pk, json_val)
+                    })?;
+                }
+            },
+            None => {
+                let start = q.after.as_ref().map_or(1, |a| a.pk + 1);
+                self.walk_resources(tx, start, |pk, data| {
+                    let (_, json_val) = decode_record(data).unwrap();
+                    call_if_matches(None, pk, json_val)
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn aggregate<T: Transaction>(&self, tx: &T, q: &Query, agg: &Aggregate) -> Result<Value, BarnError> {
+        let group_index = agg.group_by.as_ref().and_then(|g| self.indices.values().find(|i| i.at_path() == Some(g.as_str()) && !i.parts[0].multi));
+
+        // counts can be served from the DB stats and the index without reading any resource
+        if agg.op == AggregateOp::Count && q.filter.is_none() && q.conditions.is_empty() {
+            let total = self.count(tx)?;
+            if agg.group_by.is_none() {
+                return Ok(Accumulator::with_count(total).to_value(&agg.op, None));
+            }
+
+            if let Some(i) = group_index {
+                if let Some(cardinalities) = i.cardinalities(tx)? {
+                    let mut groups = vec!();
+                    let mut indexed = 0;
+                    let mut nulls = 0;
+                    for (group, count) in cardinalities {
+                        indexed += count;
+                        // null values are grouped along with the missing ones
+                        if group.is_null() {
+                            nulls = count;
+                            continue;
+                        }
+                        groups.push(Accumulator::with_count(count).to_value(&agg.op, Some(group)));
+                    }
+                    // resources without the attribute are not present in the index
+                    nulls += total - indexed;
+                    if nulls > 0 {
+                        groups.push(Accumulator::with_count(nulls).to_value(&agg.op, Some(Value::Null)));
+                    }
+                    return Ok(Value::Array(groups));
+                }
+            }
+        }
+
+        let mut total = Accumulator::default();
+        // groups are ordered by the index key if the attribute is indexed, the null group is always the last
+        let mut groups: BTreeMap<Vec<u8>, (Value, Accumulator)> = BTreeMap::new();
+        let mut null_group = Accumulator::default();
+        let accumulate = |_key: Option<&[u8]>, _pk: u64, r: &Value| -> bool {
+            let val = agg.at_path.as_ref().and_then(|p| r.pointer(p));
+            if agg.group_by.is_none() {
+                total.add(val);
+                return true;
+            }
+
+            let group = r.pointer(agg.group_by.as_ref().unwrap());
+            match group {
+                None | Some(Value::Null) => null_group.add(val),
+                Some(g) => {
+                    let mut group_key = None;
+                    let mut group_val = g.clone();
+                    // group on the same value as stored in the index to match the result of counting using the index
+                    if let Some(i) = group_index {
+                        if let Ok(k) = i.parts[0].to_key(g) {
+                            if let Some(v) = i.parts[0].decode_key(&k) {
+                                group_val = v;
+                            }
+                            group_key = Some(k);
+                        }
+                    }
+                    let group_key = group_key.unwrap_or_else(|| serde_json::to_vec(g).unwrap());
+                    groups.entry(group_key).or_insert_with(|| (group_val, Accumulator::default())).1.add(val);
+                }
+            }
+            true
+        };
+
+        self.find_matches(tx, q, accumulate)?;
+
+        if agg.group_by.is_none() {
+            return Ok(total.to_value(&agg.op, None));
+        }
+
+        let mut result: Vec<Value> = groups.into_iter().map(|(_, (g, acc))| acc.to_value(&agg.op, Some(g))).collect();
+        if null_group.count > 0 {
+            result.push(null_group.to_value(&agg.op, Some(Value::Null)));
+        }
+        Ok(Value::Array(result))
+    }
+
+    /// Number of resources stored in the barrel
+    fn count<T: Transaction>(&self, tx: &T) -> Result<u64, BarnError> {
+        let stat = tx.stat(self.db);
+        if let Err(e) = stat {
+            warn!("failed to read the stats {}", e);
+            return Err(BarnError::TxReadError);
+        }
+
+        // the key storing the last PK value is not a resource
+        Ok((stat.unwrap().entries() as u64).saturating_sub(1))
+    }
+
+    /// Selects the index whose entries are the resources that may satisfy the given conditions.
+    /// All the conditions on the attribute of the index are applied while walking it. Equality
+    /// conditions and unique indices are preferred. When sorting, the sort attribute's index is walked
+    /// and the resources are restricted to the ones found using the selected index.
+    /// None is returned if neither the conditions nor the sort can be served by an index.
+    fn find_candidates<T: Transaction>(&self, tx: &T, conditions: &[Condition], sort: Option<&Sort>) -> Result<Option<Candidates<'_>>, BarnError> {
+        // visited in the order of names to pick the same index for the same conditions, cursors depend on it
+        let mut indices: Vec<&Index> = self.indices.values().collect();
+        indices.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut selected: Option<&Index> = None;
+        let mut selected_rank = 0;
+        for i in indices {
+            let rank = i.rank(conditions);
+            if rank > selected_rank {
+                selected = Some(i);
+                selected_rank = rank;
+            }
+        }
+
+        let mut selected_bounds = None;
+        if let Some(i) = selected {
+            // when none of the values are usable the caller has to scan the resources
+            selected_bounds = i.bounds(conditions)?.map(|b| (i, b));
+        }
+
+        if sort.is_none() {
+            if let Some((i, (range, served))) = selected_bounds {
+                return Ok(Some(Candidates { index: i, range, descending: false, selected: None, with_missing: false, served }));
+            }
+            return Ok(None);
+        }
+
+        let sort = sort.unwrap();
+        let si = self.indices.values().find(|i| i.at_path() == Some(sort.at_path.as_str()) && !i.parts[0].multi);
+        if si.is_none() {
+            warn!("sorting is only supported on indexed attributes, {} is not indexed", &sort.at_path);
+            return Err(BarnError::BadSearchFilter);
+        }
+
+        let si = si.unwrap();
+        let sort_bounds = si.bounds(conditions)?;
+        // the resources missing from the sort index cannot satisfy the conditions looked up in it
+        let with_missing = sort_bounds.is_none();
+        let (range, mut served) = sort_bounds.unwrap_or(((Bound::Unbounded, Bound::Unbounded), vec!()));
+        let mut selected = None;
+        if let Some((i, (selected_range, selected_served))) = selected_bounds {
+            if !std::ptr::eq(i, si) {
+                let mut pks = HashSet::new();
+                i.walk(tx, &selected_range, false, None, |_, pk| {
+                    pks.insert(pk);
+                    true
+                })?;
+                selected = Some(pks);
+                served.extend(selected_served);
+            }
+        }
+
+        Ok(Some(Candidates { index: si, range, descending: sort.descending, selected, with_missing, served }))
+    }
+
+    /// Visits the resources in the order of PKs starting at the given PK, stops when the visitor returns false
+    fn walk_resources<T: Transaction, F>(&self, tx: &T, start: u64, mut visit: F) -> Result<(), BarnError>
+    where F: FnMut(u64, &[u8]) -> bool {
+        let cursor = tx.open_ro_cursor(self.db);
+        if let Err(e) = cursor {
+            warn!("failed to open cursor {}", e);
+            return Err(BarnError::TxReadError);
+        }
+
+        // key 0 stores the last PK and is never visited
+        let start_key = if start > 1 { start.to_le_bytes() } else { DB_READ_START_KEY };
+        for row in cursor.unwrap().iter_from(start_key) {
+            if let Err(e) = row {
+                warn!("failed to read the resource {}", e);
+                break;
+            }
+
+            let (key, data) = row.unwrap();
+            let pk = u64::from_le_bytes(key.try_into().unwrap());
+            if !visit(pk, data) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the configured metadata attributes, the creation time is carried over from the existing resource when updating
+    fn stamp(&self, d_obj: &mut Map<String, Value>, existing: Option<&Value>, version: u64) {
+        let now = Utc::now();
+        if let Some(at) = &self.metadata.created_at {
+            match existing.map(|e| e.get(at)) {
+                None => {
+                    d_obj.insert(at.clone(), self.created_at_format.format(&now));
+                },
+                Some(Some(created_at)) => {
+                    d_obj.insert(at.clone(), created_at.clone());
+                },
+                Some(None) => {
+                    d_obj.remove(at);
+                }
+            }
+        }
+
+        if let Some(at) = &self.metadata.updated_at {
+            d_obj.insert(at.clone(), self.updated_at_format.format(&now));
+        }
+
+        if let Some(at) = &self.metadata.version {
+            d_obj.insert(at.clone(), Value::from(version));
+        }
+    }
+
+    /// Finds the PK of the resource with the given value of the id attribute
+    fn resolve_id<T: Transaction>(&self, tx: &T, id: &str) -> Result<u64, BarnError> {
+        let ids_db = match self.ids_db {
+            Some(db) => db,
+            None => {
+                return id.parse::<u64>().map_err(|_| {
+                    debug!("invalid resource identifier {}", id);
+                    BarnError::ResourceNotFoundError
+                });
+            }
+        };
+
+        // generated ids are matched in their canonical form
+        let id = match self.id_strategy {
+            IdStrategy::UuidV4 | IdStrategy::UuidV7 => Uuid::parse_str(id).map(|u| u.to_string()).unwrap_or_else(|_| String::from(id)),
+            IdStrategy::Ulid => Ulid::from_string(id).map(|u| u.to_string()).unwrap_or_else(|_| String::from(id)),
+            _ => String::from(id)
+        };
+
+        match tx.get(ids_db, &id) {
+            Ok(pk) => Ok(u64::from_le_bytes(pk.try_into().unwrap())),
+            Err(e) => {
+                debug!("resource not found with identifier {}", id);
+                Err(BarnError::ResourceNotFoundError)
+            }
+        }
+    }
+
+    /// Adds the resource to the index, a unique value already held by another resource
+    /// is reported with the ID of that resource
+    fn index(&self, tx: &mut RwTransaction, i: &Index, r: &Value, pk: u64) -> Result<(), BarnError> {
+        match i.insert(tx, r, pk)? {
+            Some((value, existing_pk)) => Err(BarnError::UniqueConstraintViolation {
+                index: i.name.clone(),
+                attr_path: i.attr_paths(),
+                value: Box::new(value),
+                existing_id: Box::new(self.ext_id(tx, existing_pk)?)
+            }),
+            None => Ok(())
+        }
+    }
+
+    /// Returns the value of the ID attribute of the resource
+    fn ext_id<T: Transaction>(&self, tx: &T, pk: u64) -> Result<Value, BarnError> {
+        let r = self.get(pk, tx)?;
+        Ok(r.get(&self.id_attr_name).cloned().unwrap_or_else(|| self.pk_value(pk)))
+    }
+
+    fn pk_value(&self, pk: u64) -> Value {
+        match self.id_attr_type.as_str() {
+            "string" => {
+                Value::from(format!("{}", pk))
+            },
+            _ => {
+                Value::from(pk)
+            }
+        }
+    }
+
+    fn get<T: Transaction>(&self, id: u64, tx: &T) -> Result<Value, BarnError> {
+        self.get_versioned(id, tx).map(|(val, _)| val)
+    }
+
+    fn get_versioned<T: Transaction>(&self, id: u64, tx: &T) -> Result<(Value, u64), BarnError> {
+        if id <= 0 {
+            debug!("invalid resource identifier {}", id);
+            return Err(BarnError::ResourceNotFoundError);
+        }
+
+        let get_result = tx.get(self.db, &id.to_le_bytes());
+        match get_result {
+            Err(e) => {
+                debug!("resource not found with identifier {}", id);
+                Err(BarnError::ResourceNotFoundError)
+            },
+            Ok(data) => {
+                let val_result = decode_record(data);
+                match val_result {
+                    Ok((version, val)) => {
+                        Ok((val, version))
+                    },
+                    Err(e) => {
+                        warn!("failed to deserialize the resource with identifier {}", id);
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+}
+#[derive(Default)]
+struct Accumulator {
+    count: u64,
+    // number of numeric values seen
+    num_count: u64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>
+}
+
+impl Accumulator {
+    fn with_count(count: u64) -> Accumulator {
+        Accumulator {
+            count,
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, val: Option<&Value>) {
+        self.count += 1;
+        if let Some(f) = val.and_then(|v| v.as_f64()) {
+            self.num_count += 1;
+            self.sum += f;
+            self.min = Some(self.min.map_or(f, |m| m.min(f)));
+            self.max = Some(self.max.map_or(f, |m| m.max(f)));
+        }
+    }
+
+    fn to_value(&self, op: &AggregateOp, group: Option<Value>) -> Value {
+        let mut obj = Map::new();
+        if let Some(g) = group {
+            obj.insert(String::from("group"), g);
+        }
+        obj.insert(String::from("count"), Value::from(self.count));
+        let agg_val = match op {
+            AggregateOp::Count => None,
+            AggregateOp::Min => Some(self.min.map_or(Value::Null, Value::from)),
+            AggregateOp::Max => Some(self.max.map_or(Value::Null, Value::from)),
+            AggregateOp::Sum => Some(Value::from(self.sum)),
+            AggregateOp::Avg => {
+                if self.num_count == 0 {
+                    Some(Value::Null)
+                }
+                else {
+                    Some(Value::from(self.sum / self.num_count as f64))
+                }
+            }
+        };
+        if let Some(v) = agg_val {
+            obj.insert(String::from(op.name()), v);
+        }
+        Value::Object(obj)
+    }
+}
+
+/// Records the format version in a new environment, the environments written in other versions are
+/// refused as there is no migration
+fn check_format_version(tx: &mut RwTransaction) -> Result<(), BarnError> {
+    let main_db = unsafe { tx.open_db(None) }.map_err(|_| EnvOpenError)?;
+    let found = match tx.get(main_db, &FORMAT_VERSION_KEY) {
+        Ok(v) => v.try_into().map(u64::from_le_bytes).unwrap_or(0),
+        Err(lmdb::Error::NotFound) => {
+            let empty = match tx.open_ro_cursor(main_db) {
+                Ok(mut cursor) => cursor.iter_start().next().is_none(),
+                Err(_) => return Err(EnvOpenError)
+            };
+            if !empty {
+                1
+            }
+            else {
+                info!("writing the format version {}", FORMAT_VERSION);
+                return tx.put(main_db, &FORMAT_VERSION_KEY, &FORMAT_VERSION.to_le_bytes(), WriteFlags::empty()).map_err(|e| {
+                    warn!("{}", e);
+                    BarnError::TxWriteError
+                });
+            }
+        },
+        Err(e) => {
+            warn!("{}", e);
+            return Err(EnvOpenError);
+        }
+    };
+
+    if found != FORMAT_VERSION {
+        warn!("the environment was written in the format version {}, expected {}", found, FORMAT_VERSION);
+        return Err(BarnError::UnsupportedFormatVersion(found));
+    }
+    Ok(())
+}
+
+/// Resources are stored as their version(little endian u64) followed by the msgpack encoded data
+fn encode_record(version: u64, data: &Value) -> Result<Vec<u8>, BarnError> {
+    let mut buf: Vec<u8> = Vec::new();
+    buf.extend_from_slice(&version.to_le_bytes());
+    let ser_result = data.serialize(&mut Serializer::new(&mut buf));
+    if let Err(e) = ser_result {
+        warn!("{:#?}", e);
+        return Err(BarnError::SerializationError);
+    }
+    Ok(buf)
+}
+
+fn decode_record(data: &[u8]) -> Result<(u64, Value), BarnError> {
+    if data.len() < 8 {
+        return Err(BarnError::DeSerializationError);
+    }
+
+    let version = u64::from_le_bytes(data[..8].try_into().unwrap());
+    match rmps::from_read_ref(&data[8..]) {
+        Ok(val) => Ok((version, val)),
+        Err(e) => {
+            debug!("{:#?}", e);
+            Err(BarnError::DeSerializationError)
+        }
+    }
+}
+
+/// The key of the given id in the ids DB, only strings and numbers can be used as ids
+fn ext_id_key(id: &Value) -> Option<String> {
+    match id {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None
+    }
+}
+
+/// Separates running out of space from the other write failures
+fn write_error(e: lmdb::Error) -> BarnError {
+    match e {
+        lmdb::Error::MapFull => BarnError::DbFullError,
+        _ => BarnError::TxWriteError
+    }
+}
+
+fn max_lower(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x > y || (x == y && matches!(a, Bound::Excluded(_))) { a } else { b }
+        }
+    }
+}
+
+fn min_upper(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x < y || (x == y && matches!(a, Bound::Excluded(_))) { a } else { b }
+        }
+    }
+}
+
+/// Looks up the type and format of the attribute in the resource's schema definition
+fn key_part(schema: &Value, res_def: &Value, attr_path: &str, normalizer: Arc<dyn Normalizer>) -> KeyPart {
+    let at_path = attr_path.replace(".", "/");
+    let at_pointer = format!("/properties/{}", &at_path);
+    let mut at_def = resolve_def(schema, res_def.pointer(at_pointer.as_str()).unwrap());
+    let mut multi = false;
+    if at_def.get("type").and_then(|t| t.as_str()) == Some("array") {
+        // the elements are indexed, tuples are expected to have elements of the same type
+        let items = match at_def.get("items").unwrap() {
+            Value::Array(items) => &items[0],
+            items => items
+        };
+        at_def = resolve_def(schema, items);
+        multi = true;
+    }
+
+    let at_type_val = at_def.get("type").unwrap().as_str().unwrap();
+    let mut at_type_format : &str = "";
+    let at_format = at_def.get("format");
+    if at_format.is_some() {
+        at_type_format = at_format.unwrap().as_str().unwrap();
+    }
+
+    KeyPart {
+        // prefix with a slash to make it a valid pointer for the object
+        at_path: format!("/{}", &at_path),
+        val_type: String::from(at_type_val),
+        val_format: String::from(at_type_format),
+        multi,
+        normalizer
+    }
+}
+
+/// Derives the format of a stamped time from the attribute's schema, integers and attributes
+/// missing from the schema get milliseconds and date-time strings get RFC 3339 timestamps
+fn time_format(schema: &Value, res_def: &Value, attr: Option<&str>) -> Result<TimeFormat, BarnError> {
+    let (attr, at_def) = match attr.and_then(|at| res_def.pointer(&format!("/properties/{}", at)).map(|d| (at, d))) {
+        Some((at, d)) => (at, resolve_def(schema, d)),
+        None => return Ok(TimeFormat::Millis)
+    };
+
+    match (at_def.get("type").and_then(Value::as_str), at_def.get("format").and_then(Value::as_str)) {
+        (Some("integer"), _) | (Some("number"), _) => Ok(TimeFormat::Millis),
+        (Some("string"), Some("date-time")) => Ok(TimeFormat::DateTime),
+        _ => {
+            warn!("the metadata attribute {} must be either an integer or a date-time string", attr);
+            Err(DbConfigError)
+        }
+    }
+}
+
+impl TimeFormat {
+    fn format(&self, t: &DateTime<Utc>) -> Value {
+        match self {
+            TimeFormat::Millis => Value::from(t.timestamp_millis()),
+            TimeFormat::DateTime => Value::from(t.to_rfc3339_opts(SecondsFormat::Millis, true))
+        }
+    }
+}
+
+/// Follows the $ref of the definition if present
+fn resolve_def<'a>(schema: &'a Value, def: &'a Value) -> &'a Map<String, Value> {
+    let def = def.as_object().unwrap();
+    match def.get("$ref") {
+        Some(at_ref) => schema.pointer(at_ref.as_str().unwrap().strip_prefix("#").unwrap()).unwrap().as_object().unwrap(),
+        None => def
+    }
+}
+
+/// Returns the smallest key greater than all the keys starting with the given one,
+/// None if there is no such key
+fn successor(key: &[u8]) -> Option<Vec<u8>> {
+    let mut next = key.to_vec();
+    while let Some(b) = next.pop() {
+        if b < 0xFF {
+            next.push(b + 1);
+            return Some(next);
+        }
+    }
+    None
+}
+
+/// The key of the normalized string, a leading zero byte is escaped to keep the key apart from null
+fn text_key(normalized: &str) -> Vec<u8> {
+    let mut key_data = normalized.as_bytes().to_vec();
+    if key_data.first() == Some(&0) {
+        key_data.insert(1, 0xFF);
+    }
+    key_data
+}
+
+/// The normalized string as a component of a compound key without the terminator, zero bytes are escaped
+fn compound_text_key(normalized: &str) -> Vec<u8> {
+    let mut key_data = Vec::with_capacity(normalized.len() + 3);
+    key_data.push(1);
+    for b in normalized.bytes() {
+        key_data.push(b);
+        if b == 0 {
+            key_data.push(0xFF);
+        }
+    }
+    key_data
+}
+
+/// Positions the cursor at the first index entry at or after the key accepted by the predicate,
+/// None if there is no such entry
+fn seek<'t, C, P>(cursor: &C, key: &[u8], accept: P) -> Result<Option<(&'t [u8], u64)>, BarnError>
+where C: Cursor<'t>, P: Fn((&[u8], u64)) -> bool {
+    // an empty key cannot be looked up, all the keys sort after it
+    let mut entry = if key.is_empty() {
+        read_entry(cursor.get(None, None, MDB_FIRST))?
+    }
+    else {
+        read_entry(cursor.get(Some(key), None, MDB_SET_RANGE))?
+    };
+    while let Some(e) = entry {
+        if accept(e) {
+            break;
+        }
+        entry = read_entry(cursor.get(None, None, MDB_NEXT))?;
+    }
+    Ok(entry)
+}
+
+/// Moves the cursor to the index entry before the given position, to the last entry if the position is past the end
+fn before<'t, C: Cursor<'t>>(cursor: &C, at: Option<(&'t [u8], u64)>) -> Result<Option<(&'t [u8], u64)>, BarnError> {
+    read_entry(cursor.get(None, None, if at.is_some() { MDB_PREV } else { MDB_LAST }))
+}
+
+/// Converts the result of a cursor operation on an index to the key and the PK, None past either end
+fn read_entry<'t>(result: lmdb::Result<(Option<&'t [u8]>, &'t [u8])>) -> Result<Option<(&'t [u8], u64)>, BarnError> {
+    match result {
+        Ok((Some(key), data)) => Ok(Some((key, u64::from_le_bytes(data.try_into().unwrap())))),
+        Ok((None, _)) | Err(lmdb::Error::NotFound) => Ok(None),
+        Err(e) => {
+            warn!("failed to read index entry {}", e);
+            Err(BarnError::TxReadError)
+        }
+    }
+}
+
+/// The upper bound of the keys starting with the given prefix
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    match successor(prefix) {
+        Some(s) => Bound::Excluded(s),
+        None => Bound::Unbounded
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -530,7 +2546,7 @@ mod tests {
             }
 
             for i in &dr.1.indices {
-                let index_name = format!("{}_{}", dr.0, i.attr_path);
+                let index_name = format!("{}_{}", dr.0, i.attr_path.name());
                 let index = barrel.unwrap().indices.get(&index_name);
                 if let None = index {
                     println!("database for index {} not found", &index_name);
@@ -14,7 +14,38 @@ pub struct DbConf {
 pub struct ResourceConf {
     pub id_attr_name: Option<String>,
     pub id_attr_type: Option<String>,
-    pub indices: Vec<IndexConf>
+    #[serde(default)]
+    pub id_strategy: IdStrategy,
+    pub indices: Vec<IndexConf>,
+    #[serde(default)]
+    pub metadata: MetadataConf
+}
+
+/// How the values of the id attribute are assigned, resources are always stored under a u64 PK
+/// and all the strategies other than sequence map the id to the PK
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdStrategy {
+    /// the PK itself
+    #[default]
+    Sequence,
+    /// the value given by the client, must be unique
+    Client,
+    UuidV4,
+    UuidV7,
+    Ulid
+}
+
+/// Names of the attributes stamped by barn on insert and update, client supplied values are overridden.
+/// Times are stamped as RFC 3339 strings on the attributes of date-time format and as milliseconds
+/// since epoch otherwise
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MetadataConf {
+    /// creation time
+    pub created_at: Option<String>,
+    /// last modified time
+    pub updated_at: Option<String>,
+    pub version: Option<String>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -25,6 +56,34 @@ pub struct ResourceDefaults {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IndexConf {
-    pub attr_path: String,
-    pub unique: Option<bool>
+    pub attr_path: AttrPath,
+    pub unique: Option<bool>,
+    /// index the resources missing the attribute under null, defaults to false
+    pub index_missing: Option<bool>,
+    /// name of the normalizer applied to string values, names separated by commas are chained.
+    /// One of none, lowercase (the default), nfkc_casefold, strip_accents and collapse_whitespace
+    pub normalizer: Option<String>
+}
+
+/// The indexed attribute, or the list of attributes of a compound index whose keys
+/// are ordered by the first attribute, then by the second and so on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AttrPath {
+    Single(String),
+    Compound(Vec<String>)
+}
+
+impl AttrPath {
+    pub fn paths(&self) -> Vec<&str> {
+        match self {
+            AttrPath::Single(p) => vec!(p.as_str()),
+            AttrPath::Compound(paths) => paths.iter().map(|p| p.as_str()).collect()
+        }
+    }
+
+    /// The name used in the index's DB name
+    pub fn name(&self) -> String {
+        self.paths().join("+")
+    }
 }
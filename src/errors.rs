@@ -1,5 +1,16 @@
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
+use serde::Serialize;
+use serde_json::{json, Value};
 use thiserror::Error;
 
+/// A schema validation failure of a single attribute
+#[derive(Debug, Serialize)]
+pub struct Violation {
+    pub instance_path: String,
+    pub message: String
+}
+
 #[derive(Debug, Error)]
 pub enum BarnError {
     #[error("invalid resource, config validation failed")]
@@ -45,5 +56,91 @@ pub enum BarnError {
     UnsupportedIndexValueType,
 
     #[error("bad search filter")]
-    BadSearchFilter
+    BadSearchFilter,
+
+    #[error("invalid patch document")]
+    InvalidPatchError,
+
+    #[error("malformed request body, {0}")]
+    MalformedBodyError(String),
+
+    #[error("request body is larger than {0} bytes")]
+    PayloadTooLargeError(usize),
+
+    #[error("value {value} at {attr_path} is already used by the resource {existing_id}, violates the unique index {index}")]
+    UniqueConstraintViolation {
+        index: String,
+        attr_path: String,
+        value: Box<Value>,
+        existing_id: Box<Value>
+    },
+
+    #[error("database is full")]
+    DbFullError,
+
+    #[error("no unique index found on the attribute {0}")]
+    NoUniqueIndexError(String),
+
+    #[error("missing or invalid value for the id attribute")]
+    InvalidIdError,
+
+    #[error("precondition failed, the current version is {0}")]
+    PreconditionFailed(u64),
+
+    #[error("resource failed schema validation")]
+    SchemaValidationError(Vec<Violation>),
+
+    #[error("the data is stored in the unsupported format version {0}")]
+    UnsupportedFormatVersion(u64)
+}
+
+impl BarnError {
+    /// Builds the problem details document(RFC 7807) describing this error
+    pub fn to_problem(&self) -> Value {
+        let status = self.status_code();
+        let mut problem = json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or(""),
+            "status": status.as_u16(),
+            "detail": self.to_string()
+        });
+
+        match self {
+            BarnError::UniqueConstraintViolation { index, attr_path, value, existing_id } => {
+                problem["index"] = Value::from(index.as_str());
+                problem["attr_path"] = Value::from(attr_path.as_str());
+                problem["value"] = value.as_ref().clone();
+                problem["existing_id"] = existing_id.as_ref().clone();
+            },
+            BarnError::SchemaValidationError(violations) => {
+                problem["errors"] = json!(violations);
+            },
+            _ => {}
+        }
+        problem
+    }
+}
+
+impl ResponseError for BarnError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            BarnError::UnknownResourceName | BarnError::ResourceNotFoundError => StatusCode::NOT_FOUND,
+            BarnError::UniqueConstraintViolation { .. } => StatusCode::CONFLICT,
+            BarnError::DbFullError => StatusCode::INSUFFICIENT_STORAGE,
+            BarnError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            BarnError::PayloadTooLargeError(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            BarnError::SchemaValidationError(_) | BarnError::InvalidResourceError
+            | BarnError::InvalidResourceDataError | BarnError::InvalidIdError => StatusCode::UNPROCESSABLE_ENTITY,
+            BarnError::InvalidAttributeValueError | BarnError::UnsupportedIndexValueType
+            | BarnError::BadSearchFilter | BarnError::InvalidPatchError | BarnError::NoUniqueIndexError(_)
+            | BarnError::MalformedBodyError(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/problem+json")
+            .body(self.to_problem().to_string())
+    }
 }
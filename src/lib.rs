@@ -1,5 +1,6 @@
-use actix_web::{get, post, web, HttpRequest, Responder, HttpResponse, Either};
+use actix_web::{get, post, put, patch, delete, web, HttpMessage, HttpRequest, Responder, HttpResponse, ResponseError, Either};
 use actix_web::web::*;
+use actix_web::http::header::{HeaderName, ETAG, IF_MATCH, IF_NONE_MATCH, LINK, LOCATION};
 use log::{warn};
 extern crate rmp_serde as rmps;
 pub mod barn;
@@ -7,13 +8,18 @@ mod yard;
 pub mod schema;
 pub mod errors;
 pub mod conf;
+pub mod query;
+pub mod normalizer;
 
 pub use barn::*;
 pub use crate::schema::*;
+use crate::errors::{BarnError, Violation};
+use crate::query::{project, to_pointer, Aggregate, AggregateOp, Condition, Cursor, Op, Sort};
+use std::convert::TryInto;
 use std::sync::Arc;
 use std::sync::mpsc::{Sender, Receiver, channel};
-use serde_json::Value;
-use futures::Stream;
+use serde_json::{json, Value};
+use futures::{Stream, StreamExt};
 use futures::task::{Context, Poll};
 use std::pin::Pin;
 use serde::{Deserialize};
@@ -21,7 +27,27 @@ use serde::{Deserialize};
 #[derive(Clone)]
 pub struct AppData<'a> {
     pub barn: Arc<barn::Barn>,
-    pub validator: Arc<jsonschema_valid::Config<'a>>
+    pub validator: Arc<jsonschema_valid::Config<'a>>,
+    /// maximum size in bytes of the JSON arrays accepted by the bulk insert
+    pub bulk_limit: usize
+}
+
+fn validate(validator: &jsonschema_valid::Config, r: &Value) -> Result<(), BarnError> {
+    let valid = validator.validate(r);
+    if let Err(e) = valid {
+        let mut violations = vec!();
+        for i in e {
+            warn!("validation error: {} {}", &i.instance_path.join("/"), &i.msg);
+            let mut instance_path = String::new();
+            for p in &i.instance_path {
+                instance_path.push('/');
+                instance_path.push_str(p);
+            }
+            violations.push(Violation { instance_path, message: i.msg.clone() });
+        }
+        return Err(BarnError::SchemaValidationError(violations));
+    }
+    Ok(())
 }
 
 #[get("/")]
@@ -30,53 +56,704 @@ pub async fn echo(ad: web::Data<AppData<'_>>) -> impl Responder {
     HttpResponse::Ok().body(format!("{:#?}", t))
 }
 
+/// Responds with the stored resource, including the generated id, unless the client prefers a minimal response
 #[post("/{name}")]
-pub async fn insert(r: Json<Value>, Path(res_name): Path<String>, req: HttpRequest, ad: Data<AppData<'_>>) -> impl Responder {
+pub async fn insert(r: Json<Value>, Path(res_name): Path<String>, req: HttpRequest, ad: Data<AppData<'_>>) -> HttpResponse {
     let mut r = r.into_inner();
-    let valid = ad.validator.validate(&r);
-    if let Err(e) = valid {
-        for i in e {
-            warn!("validation error: {} {}", &i.instance_path.join("/"), &i.msg);
+    if let Err(e) = validate(&ad.validator, &r) {
+        return e.error_response();
+    }
+    let location = format!("/{}", &res_name);
+    let insert_result = ad.barn.insert(res_name.clone(), &mut r);
+    if let Err(e) = insert_result {
+        warn!("{}", e);
+        return e.error_response();
+    }
+
+    let id = inserted_id(&ad.barn, &res_name, &r, insert_result.unwrap());
+    let mut resp = HttpResponse::Created();
+    resp.header(LOCATION, format!("{}/{}", location, id_string(&id)));
+    resp.header(ETAG, etag(FIRST_VERSION));
+    if prefers_minimal(&req) {
+        return resp.header("Preference-Applied", "return=minimal").finish();
+    }
+
+    resp.json(r)
+}
+
+/// The value of the id attribute assigned on insert, which is not the PK for all id strategies
+fn inserted_id(barn: &Barn, res_name: &str, r: &Value, pk: u64) -> Value {
+    barn.id_attr_name(res_name).and_then(|at| r.get(at)).cloned().unwrap_or_else(|| Value::from(pk))
+}
+
+fn id_string(id: &Value) -> String {
+    match id {
+        Value::String(s) => s.clone(),
+        _ => id.to_string()
+    }
+}
+
+/// ETags are the quoted versions of the resources
+fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Parses the list of entity tags present in the given header, tags that were not issued by barn never match
+fn parse_version_match(req: &HttpRequest, name: HeaderName) -> Option<VersionMatch> {
+    let h = req.headers().get(name)?;
+    let h = h.to_str().unwrap_or("");
+    if h.trim() == "*" {
+        return Some(VersionMatch::Any);
+    }
+
+    let versions = h.split(',')
+        .map(|t| t.trim())
+        .map(|t| t.strip_prefix("W/").unwrap_or(t))
+        .filter_map(|t| t.trim_matches('"').parse::<u64>().ok())
+        .collect();
+    Some(VersionMatch::Versions(versions))
+}
+
+fn precondition(req: &HttpRequest) -> Precondition {
+    Precondition {
+        if_match: parse_version_match(req, IF_MATCH),
+        if_none_match: parse_version_match(req, IF_NONE_MATCH)
+    }
+}
+
+/// Checks for `return=minimal` in the Prefer header(RFC 7240)
+fn prefers_minimal(req: &HttpRequest) -> bool {
+    req.headers().get_all("Prefer")
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|p| p.trim().eq_ignore_ascii_case("return=minimal"))
+}
+
+#[derive(Deserialize)]
+struct GetRequest {
+    fields: Option<String>
+}
+
+#[derive(Deserialize)]
+struct BulkRequest {
+    batch_size: Option<usize>
+}
+
+/// Accepts either a JSON array or newline delimited JSON, responds with the status of each resource
+/// in the same order as they were given. Newline delimited JSON is parsed line by line as the body
+/// is received, arrays are read whole and are limited to `AppData::bulk_limit` bytes
+#[post("/{name}/_bulk")]
+pub async fn bulk_insert(mut body: web::Payload, Path(res_name): Path<String>, query: Query<BulkRequest>, req: HttpRequest, ad: Data<AppData<'_>>) -> HttpResponse {
+    let ndjson = req.content_type() == "application/x-ndjson";
+    let mut records: Vec<Value> = vec!();
+    let mut buf = BytesMut::new();
+    let mut line_num = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("failed to read the body {}", e);
+                return BarnError::MalformedBodyError(e.to_string()).error_response();
+            }
+        };
+        buf.extend_from_slice(&chunk);
+        if ndjson {
+            while let Some(end) = buf.iter().position(|b| *b == b'\n') {
+                let line = buf.split_to(end + 1);
+                line_num += 1;
+                if let Err(e) = parse_line(&line, &mut records) {
+                    warn!("failed to parse the line {} {}", line_num, e);
+                    return BarnError::MalformedBodyError(format!("line {}: {}", line_num, e)).error_response();
+                }
+            }
+        }
+        else if buf.len() > ad.bulk_limit {
+            warn!("the array of resources exceeds {} bytes", ad.bulk_limit);
+            return BarnError::PayloadTooLargeError(ad.bulk_limit).error_response();
+        }
+    }
+
+    if ndjson {
+        if let Err(e) = parse_line(&buf, &mut records) {
+            warn!("failed to parse the line {} {}", line_num + 1, e);
+            return BarnError::MalformedBodyError(format!("line {}: {}", line_num + 1, e)).error_response();
+        }
+    }
+    else {
+        match serde_json::from_slice(&buf) {
+            Ok(v) => records = v,
+            Err(e) => {
+                warn!("failed to parse the array of resources {}", e);
+                return BarnError::MalformedBodyError(e.to_string()).error_response();
+            }
+        }
+    }
+
+    let mut statuses: Vec<Option<Value>> = vec!();
+    let mut valid_records = vec!();
+    for r in records {
+        match validate(&ad.validator, &r) {
+            Ok(_) => {
+                statuses.push(None);
+                valid_records.push(r);
+            },
+            Err(e) => {
+                statuses.push(Some(e.to_problem()));
+            }
         }
-        return HttpResponse::BadRequest();
     }
-    drop(valid);
-    let insert_result = ad.barn.insert(res_name, &mut r);
+
+    let insert_result = ad.barn.insert_many(res_name.clone(), &mut valid_records, query.batch_size.unwrap_or(0));
     if let Err(e) = insert_result {
         warn!("{}", e);
-        return HttpResponse::InternalServerError();
+        return e.error_response();
     }
 
-    HttpResponse::Created()
+    let mut inserted = insert_result.unwrap().into_iter().zip(valid_records.iter());
+    let statuses: Vec<Value> = statuses.into_iter().map(|st| {
+        st.unwrap_or_else(|| {
+            let (insert_result, r) = inserted.next().unwrap();
+            match insert_result {
+                Ok(pk) => json!({"status": 201, "id": inserted_id(&ad.barn, &res_name, r, pk)}),
+                Err(e) => e.to_problem()
+            }
+        })
+    }).collect();
+
+    HttpResponse::Ok().json(statuses)
+}
+
+/// Parses a line of newline delimited JSON, blank lines are skipped
+fn parse_line(line: &[u8], records: &mut Vec<Value>) -> Result<(), serde_json::Error> {
+    if line.iter().all(|b| b.is_ascii_whitespace()) {
+        return Ok(());
+    }
+    records.push(serde_json::from_slice(line)?);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TxOp {
+    Insert,
+    Get,
+    Update,
+    Delete
+}
+
+#[derive(Deserialize)]
+struct TxOperation {
+    op: TxOp,
+    resource: String,
+    id: Option<Value>,
+    data: Option<Value>,
+    // expected version of the resource for updates and deletes
+    version: Option<u64>
+}
+
+/// Executes the given list of operations in a single transaction, either all of them are applied or none.
+/// Responds with the result of each operation in the same order, or with the error and the position
+/// of the operation that caused the transaction to be rolled back.
+#[post("/_tx")]
+pub async fn transaction(ops: Json<Vec<TxOperation>>, ad: Data<AppData<'_>>) -> HttpResponse {
+    let mut ops = ops.into_inner();
+    for (pos, o) in ops.iter().enumerate() {
+        let invalid = match o.op {
+            TxOp::Insert => o.data.is_none(),
+            TxOp::Update => o.data.is_none() || o.id.is_none(),
+            TxOp::Get | TxOp::Delete => o.id.is_none()
+        };
+        if invalid {
+            warn!("missing id or data in the operation at {}", pos);
+            return op_error_response(&BarnError::MalformedBodyError(String::from("missing id or data")), pos);
+        }
+        if let Some(r) = &o.data {
+            if let Err(e) = validate(&ad.validator, r) {
+                return op_error_response(&e, pos);
+            }
+        }
+    }
+
+    let mut failed_at = 0;
+    let tx_result = ad.barn.transaction(|txn| {
+        let mut results = vec!();
+        for (pos, o) in ops.iter_mut().enumerate() {
+            failed_at = pos;
+            let res_name = o.resource.clone();
+            let pre = o.version.map(Precondition::version).unwrap_or_default();
+            // the id of an insert is assigned by the ID strategy, it is not looked up
+            let pk = match (&o.op, &o.id) {
+                (TxOp::Get | TxOp::Update | TxOp::Delete, Some(id)) => txn.resolve_id(res_name.clone(), &id_string(id))?,
+                _ => 0
+            };
+            let st = match o.op {
+                TxOp::Insert => {
+                    let r = o.data.as_mut().unwrap();
+                    let pk = txn.insert(res_name, r)?;
+                    json!({"status": 201, "id": inserted_id(&ad.barn, &o.resource, r, pk), "version": FIRST_VERSION})
+                },
+                TxOp::Get => {
+                    let (val, version) = txn.get_versioned(pk, res_name)?;
+                    json!({"status": 200, "data": val, "version": version})
+                },
+                TxOp::Update => {
+                    let version = txn.update(res_name, pk, o.data.as_mut().unwrap(), &pre)?;
+                    json!({"status": 200, "version": version})
+                },
+                TxOp::Delete => {
+                    let val = txn.delete(res_name, pk, &pre)?;
+                    json!({"status": 200, "data": val})
+                }
+            };
+            results.push(st);
+        }
+        Ok(results)
+    });
+
+    match tx_result {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(e) => {
+            warn!("{}", e);
+            op_error_response(&e, failed_at)
+        }
+    }
+}
+
+/// The error response of a transaction with the position of the failed operation
+fn op_error_response(e: &BarnError, pos: usize) -> HttpResponse {
+    let mut problem = e.to_problem();
+    problem["op"] = Value::from(pos);
+    HttpResponse::build(e.status_code()).content_type("application/problem+json").json(problem)
 }
 
 #[get("/{name}/{id}")]
-pub async fn get(Path((res_name, res_id)): Path<(String, u64)>, req: HttpRequest, ad: Data<AppData<'_>>) -> HttpResponse {
-    let get_result = ad.barn.get(res_id, res_name);
+pub async fn get(Path((res_name, res_id)): Path<(String, String)>, query: Query<GetRequest>, req: HttpRequest, ad: Data<AppData<'_>>) -> HttpResponse {
+    let res_id = match ad.barn.resolve_id(res_name.clone(), &res_id) {
+        Ok(pk) => pk,
+        Err(e) => {
+            warn!("{}", e);
+            return e.error_response();
+        }
+    };
+    let get_result = ad.barn.get_versioned(res_id, res_name);
     if let Err(e) = get_result {
         warn!("{}", e);
-        return HttpResponse::NotFound().finish();
+        return e.error_response();
+    }
+
+    let (mut val, version) = get_result.unwrap();
+    let pre = precondition(&req);
+    if let Err(e) = pre.check(version) {
+        // If-Match is evaluated first, when it passes the failure means the client's copy is still current
+        if pre.if_match.as_ref().is_none_or(|m| m.contains(version)) {
+            return HttpResponse::NotModified().header(ETAG, etag(version)).finish();
+        }
+        return e.error_response();
+    }
+
+    if let Some(f) = &query.fields {
+        val = project(&val, &parse_fields(f));
+    }
+
+    HttpResponse::Ok().header(ETAG, etag(version)).json(val)
+}
+
+/// Fetches the resource by the value of an attribute having a unique index
+#[get("/{name}/by/{attr}/{value}")]
+pub async fn get_by(Path((res_name, attr, value)): Path<(String, String, String)>, query: Query<GetRequest>, ad: Data<AppData<'_>>) -> HttpResponse {
+    let get_result = ad.barn.get_by(res_name, &attr, &Value::from(value));
+    if let Err(e) = get_result {
+        warn!("{}", e);
+        return e.error_response();
+    }
+
+    let mut val = get_result.unwrap();
+    if let Some(f) = &query.fields {
+        val = project(&val, &parse_fields(f));
+    }
+
+    HttpResponse::Ok().json(val)
+}
+
+#[derive(Deserialize)]
+struct MultiGetRequest {
+    ids: Vec<Value>,
+    fields: Option<Vec<String>>
+}
+
+#[post("/{name}/_mget")]
+pub async fn multi_get(r: Json<MultiGetRequest>, Path(res_name): Path<String>, ad: Data<AppData<'_>>) -> HttpResponse {
+    let r = r.into_inner();
+    let fields: Vec<String> = r.fields.unwrap_or_default().iter().map(|f| to_pointer(f)).collect();
+    get_many(res_name, r.ids, &fields, &ad)
+}
+
+/// Responds with the resources found, in the order of the given ids, and the ids that were not found
+fn get_many(res_name: String, ids: Vec<Value>, fields: &[String], ad: &AppData) -> HttpResponse {
+    let id_strings: Vec<String> = ids.iter().map(id_string).collect();
+    let get_result = ad.barn.get_many(res_name, &id_strings);
+    if let Err(e) = get_result {
+        warn!("{}", e);
+        return e.error_response();
+    }
+
+    let mut found = vec!();
+    let mut missing = vec!();
+    for (id, (_, val)) in ids.into_iter().zip(get_result.unwrap()) {
+        match val {
+            Some(val) if fields.is_empty() => found.push(val),
+            Some(val) => found.push(project(&val, fields)),
+            None => missing.push(id)
+        }
+    }
+
+    HttpResponse::Ok().json(json!({"found": found, "missing": missing}))
+}
+
+/// Parses a comma separated list of dotted attribute paths or JSON pointers
+fn parse_fields(f: &str) -> Vec<String> {
+    f.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()).map(to_pointer).collect()
+}
+
+#[put("/{name}/{id}")]
+pub async fn update(r: Json<Value>, Path((res_name, res_id)): Path<(String, String)>, req: HttpRequest, ad: Data<AppData<'_>>) -> HttpResponse {
+    let mut r = r.into_inner();
+    if let Err(e) = validate(&ad.validator, &r) {
+        return e.error_response();
+    }
+    let res_id = match ad.barn.resolve_id(res_name.clone(), &res_id) {
+        Ok(pk) => pk,
+        Err(e) => {
+            warn!("{}", e);
+            return e.error_response();
+        }
+    };
+    let update_result = ad.barn.update(res_name, res_id, &mut r, &precondition(&req));
+    if let Err(e) = update_result {
+        warn!("{}", e);
+        return e.error_response();
+    }
+
+    HttpResponse::Ok().header(ETAG, etag(update_result.unwrap())).finish()
+}
+
+#[derive(Deserialize)]
+struct UpsertRequest {
+    by: String
+}
+
+/// Updates the resource having the same value of the unique attribute given in `by` or creates it
+#[put("/{name}")]
+pub async fn upsert(r: Json<Value>, Path(res_name): Path<String>, query: Query<UpsertRequest>, req: HttpRequest, ad: Data<AppData<'_>>) -> HttpResponse {
+    let mut r = r.into_inner();
+    if let Err(e) = validate(&ad.validator, &r) {
+        return e.error_response();
+    }
+    let upsert_result = ad.barn.upsert(res_name.clone(), &query.by, &mut r);
+    if let Err(e) = upsert_result {
+        warn!("{}", e);
+        return e.error_response();
+    }
+
+    let upserted = upsert_result.unwrap();
+    let mut resp = HttpResponse::Ok();
+    if upserted.created {
+        resp = HttpResponse::Created();
+        let id = inserted_id(&ad.barn, &res_name, &r, upserted.pk);
+        resp.header(LOCATION, format!("/{}/{}", &res_name, id_string(&id)));
+    }
+    resp.header(ETAG, etag(upserted.version));
+    if prefers_minimal(&req) {
+        return resp.header("Preference-Applied", "return=minimal").finish();
+    }
+
+    resp.json(r)
+}
+
+#[patch("/{name}/{id}")]
+pub async fn patch(body: Bytes, Path((res_name, res_id)): Path<(String, String)>, req: HttpRequest, ad: Data<AppData<'_>>) -> HttpResponse {
+    let patch_doc: Value = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to parse the patch document {}", e);
+            return BarnError::MalformedBodyError(e.to_string()).error_response();
+        }
+    };
+
+    let res_id = match ad.barn.resolve_id(res_name.clone(), &res_id) {
+        Ok(pk) => pk,
+        Err(e) => {
+            warn!("{}", e);
+            return e.error_response();
+        }
+    };
+
+    let validator = &ad.validator;
+    let pre = precondition(&req);
+    let patch_result = match req.content_type() {
+        "application/json-patch+json" => {
+            let json_patch = json_patch::from_value(patch_doc);
+            if let Err(e) = json_patch {
+                warn!("invalid JSON patch {}", e);
+                return BarnError::InvalidPatchError.error_response();
+            }
+            let json_patch = json_patch.unwrap();
+            ad.barn.patch(res_name, res_id, &pre, |r| {
+                if let Err(e) = json_patch::patch(r, &json_patch) {
+                    warn!("failed to apply the JSON patch {}", e);
+                    return Err(BarnError::InvalidPatchError);
+                }
+                validate(validator, r)
+            })
+        },
+        "application/merge-patch+json" => {
+            ad.barn.patch(res_name, res_id, &pre, |r| {
+                json_patch::merge(r, &patch_doc);
+                validate(validator, r)
+            })
+        },
+        ct => {
+            warn!("unsupported content type {} for patching", ct);
+            return HttpResponse::UnsupportedMediaType().finish();
+        }
+    };
+
+    match patch_result {
+        Ok((val, version)) => HttpResponse::Ok().header(ETAG, etag(version)).json(val),
+        Err(e) => {
+            warn!("{}", e);
+            e.error_response()
+        }
+    }
+}
+
+#[delete("/{name}/{id}")]
+pub async fn delete(Path((res_name, res_id)): Path<(String, String)>, req: HttpRequest, ad: Data<AppData<'_>>) -> HttpResponse {
+    let res_id = match ad.barn.resolve_id(res_name.clone(), &res_id) {
+        Ok(pk) => pk,
+        Err(e) => {
+            warn!("{}", e);
+            return e.error_response();
+        }
+    };
+    let delete_result = ad.barn.delete(res_name, res_id, &precondition(&req));
+    if let Err(e) = delete_result {
+        warn!("{}", e);
+        return e.error_response();
     }
 
-    HttpResponse::Ok().json(get_result.unwrap())
+    HttpResponse::NoContent().finish()
 }
 
 #[derive(Deserialize)]
 struct SearchRequest {
-    q: String
+    q: Option<String>,
+    attr: Option<String>,
+    eq: Option<String>,
+    gt: Option<String>,
+    ge: Option<String>,
+    lt: Option<String>,
+    le: Option<String>,
+    between: Option<String>,
+    starts_with: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    cursor: Option<String>,
+    sort: Option<String>,
+    fields: Option<String>,
+    // comma separated ids, fetches the resources instead of searching
+    ids: Option<String>
+}
+
+impl SearchRequest {
+    fn into_query(self) -> Result<crate::query::Query, BarnError> {
+        let mut conditions = vec!();
+        let ops = vec!((Op::Eq, self.eq), (Op::Gt, self.gt), (Op::Ge, self.ge), (Op::Lt, self.lt), (Op::Le, self.le));
+        for (op, val) in ops {
+            if let Some(v) = val {
+                let attr = self.attr.as_ref().ok_or(BarnError::BadSearchFilter)?;
+                conditions.push(Condition::new(attr, op, parse_param(&v)));
+            }
+        }
+
+        if let Some(range) = self.between {
+            let attr = self.attr.as_ref().ok_or(BarnError::BadSearchFilter)?;
+            let bounds: Vec<&str> = range.splitn(2, ',').collect();
+            if bounds.len() != 2 {
+                return Err(BarnError::BadSearchFilter);
+            }
+            conditions.extend(Condition::between(attr, parse_param(bounds[0]), parse_param(bounds[1])));
+        }
+
+        // the prefix is always a string, e.g. starts_with=acm
+        if let Some(prefix) = self.starts_with {
+            let attr = self.attr.as_ref().ok_or(BarnError::BadSearchFilter)?;
+            conditions.push(Condition::starts_with(attr, &prefix));
+        }
+
+        let mut after = None;
+        if let Some(c) = self.cursor {
+            after = Some(decode_cursor(&c)?);
+        }
+
+        // a leading - sorts in descending order, e.g. sort=-created_at
+        let sort = self.sort.map(|s| {
+            match s.strip_prefix('-') {
+                Some(attr) => Sort::desc(attr),
+                None => Sort::asc(&s)
+            }
+        });
+
+        Ok(crate::query::Query {
+            filter: self.q,
+            conditions,
+            limit: self.limit,
+            offset: self.offset.unwrap_or(0),
+            after,
+            sort,
+            fields: self.fields.as_deref().map(parse_fields).unwrap_or_default()
+        })
+    }
+}
+
+/// The continuation cursor is the URL safe base64 encoding of the last PK sent followed by
+/// the index key it was found under
+fn encode_cursor(c: &Cursor) -> String {
+    let mut data = c.pk.to_le_bytes().to_vec();
+    if let Some(key) = &c.key {
+        data.extend(key);
+    }
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_cursor(c: &str) -> Result<Cursor, BarnError> {
+    let data = base64::decode_config(c, base64::URL_SAFE_NO_PAD);
+    match data {
+        Ok(d) if d.len() >= 8 => {
+            let pk = u64::from_le_bytes(d[..8].try_into().unwrap());
+            // index keys are never empty
+            let key = if d.len() > 8 { Some(d[8..].to_vec()) } else { None };
+            Ok(Cursor { key, pk })
+        },
+        _ => {
+            warn!("invalid cursor {}", c);
+            Err(BarnError::BadSearchFilter)
+        }
+    }
+}
+
+/// Link to the next page, retains all the query parameters except the offset which
+/// was already applied to the current page
+fn next_page_link(req: &HttpRequest, after: &Cursor) -> String {
+    let mut params: Vec<&str> = req.query_string().split('&')
+        .filter(|p| !p.is_empty() && !p.starts_with("cursor=") && !p.starts_with("offset="))
+        .collect();
+    let cursor_param = format!("cursor={}", encode_cursor(after));
+    params.push(cursor_param.as_str());
+    format!("<{}?{}>; rel=\"next\"", req.path(), params.join("&"))
+}
+
+/// Values of the query parameters are treated as JSON if they parse, e.g. `10` is a number and `"10"` a string,
+/// otherwise as strings
+fn parse_param(v: &str) -> Value {
+    serde_json::from_str(v).unwrap_or_else(|_| Value::from(v))
+}
+
+#[derive(Deserialize)]
+struct AggregateRequest {
+    op: Option<String>,
+    of: Option<String>,
+    group_by: Option<String>
+}
+
+#[get("/{name}/_count")]
+pub async fn count(Path(res_name): Path<String>, query: Query<SearchRequest>, req: HttpRequest, ad: Data<AppData<'_>>) -> HttpResponse {
+    let agg_req = Query::<AggregateRequest>::from_query(req.query_string());
+    if let Err(e) = agg_req {
+        warn!("{}", e);
+        return BarnError::BadSearchFilter.error_response();
+    }
+
+    let mut agg = Aggregate::count();
+    if let Some(g) = &agg_req.unwrap().group_by {
+        agg = agg.group_by(g);
+    }
+    run_aggregate(res_name, query.into_inner(), agg, &ad)
+}
+
+#[get("/{name}/_aggregate")]
+pub async fn aggregate(Path(res_name): Path<String>, query: Query<SearchRequest>, req: HttpRequest, ad: Data<AppData<'_>>) -> HttpResponse {
+    let agg_req = Query::<AggregateRequest>::from_query(req.query_string());
+    if let Err(e) = agg_req {
+        warn!("{}", e);
+        return BarnError::BadSearchFilter.error_response();
+    }
+
+    let agg_req = agg_req.unwrap().into_inner();
+    let op = agg_req.op.as_deref().and_then(AggregateOp::from_name);
+    if op.is_none() {
+        warn!("invalid aggregate operation {:?}", agg_req.op);
+        return BarnError::BadSearchFilter.error_response();
+    }
+
+    let op = op.unwrap();
+    let mut agg = Aggregate::count();
+    if let Some(attr) = &agg_req.of {
+        agg = Aggregate::new(op, attr);
+    }
+    else if op != AggregateOp::Count {
+        warn!("attribute to be aggregated is missing");
+        return BarnError::BadSearchFilter.error_response();
+    }
+    if let Some(g) = &agg_req.group_by {
+        agg = agg.group_by(g);
+    }
+    run_aggregate(res_name, query.into_inner(), agg, &ad)
+}
+
+fn run_aggregate(res_name: String, sr: SearchRequest, agg: Aggregate, ad: &AppData) -> HttpResponse {
+    let q = sr.into_query();
+    if let Err(e) = q {
+        warn!("{}", e);
+        return e.error_response();
+    }
+
+    let agg_result = ad.barn.aggregate(res_name, q.unwrap(), agg);
+    match agg_result {
+        Ok(val) => HttpResponse::Ok().json(val),
+        Err(e) => {
+            warn!("{}", e);
+            e.error_response()
+        }
+    }
 }
 
 #[get("/{name}")]
 pub async fn search(Path(res_name): Path<String>, query: Query<SearchRequest>, req: HttpRequest, ad: Data<AppData<'_>>) -> HttpResponse {
+    if let Some(ids) = &query.ids {
+        let ids = ids.split(',').map(|id| id.trim()).filter(|id| !id.is_empty()).map(parse_param).collect();
+        let fields = query.fields.as_deref().map(parse_fields).unwrap_or_default();
+        return get_many(res_name, ids, &fields, &ad);
+    }
+
+    let q = query.into_inner().into_query();
+    if let Err(e) = q {
+        warn!("{}", e);
+        return e.error_response();
+    }
+
     let (sn, rc) = channel();
-    let get_result = ad.barn.search(res_name, query.0.q, sn);
-    if let Err(e) = get_result {
+    let search_result = ad.barn.search(res_name, q.unwrap(), sn);
+    if let Err(e) = search_result {
         warn!("{}", e);
-        return HttpResponse::NotFound().finish();
+        return e.error_response();
     }
 
-    HttpResponse::Ok()
-        .content_type("application/json")
+    let mut resp = HttpResponse::Ok();
+    if let Some(after) = search_result.unwrap() {
+        resp.header(LINK, next_page_link(&req, &after));
+    }
+
+    resp.content_type("application/json")
         .streaming(futures::stream::iter(rc))
 }
-
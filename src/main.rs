@@ -40,6 +40,12 @@ async fn main() -> std::io::Result<()> {
             .help("path to the DB config file")
             .takes_value(true)
             .default_value("config/db-conf.json"))
+        .arg(Arg::with_name("b")
+            .short("b")
+            .long("bulk-limit")
+            .help("maximum size in bytes of the JSON arrays given to the bulk insert")
+            .takes_value(true)
+            .default_value("67108864"))
         .get_matches();
 
     let env_dir = matches.value_of("d").unwrap();
@@ -51,6 +57,8 @@ async fn main() -> std::io::Result<()> {
     let db_conf_file = matches.value_of("c").unwrap();
     info!("using db conf file {}", db_conf_file);
 
+    let bulk_limit: usize = matches.value_of("b").unwrap().parse().unwrap();
+
     let db_conf_file = fs::File::open(db_conf_file).unwrap();
     let db_conf = serde_json::from_reader(db_conf_file).unwrap();
 
@@ -61,7 +69,8 @@ async fn main() -> std::io::Result<()> {
     let validator = jsonschema_valid::Config::from_schema(s_ref, draft).unwrap();
     let ad: AppData = barn::AppData {
         barn: Arc::new(barn),
-        validator: Arc::new(validator)
+        validator: Arc::new(validator),
+        bulk_limit
     };
 
     HttpServer::new(move ||{
@@ -70,9 +79,21 @@ async fn main() -> std::io::Result<()> {
             .app_data(web::JsonConfig::default())
             .app_data(web::QueryConfig::default())
             .service(barn::echo)
+            // must be registered before insert to avoid treating _tx as a resource name
+            .service(barn::transaction)
             .service(barn::insert)
+            .service(barn::bulk_insert)
+            .service(barn::multi_get)
+            // must be registered before get to avoid treating _count and _aggregate as identifiers
+            .service(barn::count)
+            .service(barn::aggregate)
             .service(barn::get)
+            .service(barn::get_by)
             .service(barn::search)
+            .service(barn::update)
+            .service(barn::upsert)
+            .service(barn::delete)
+            .service(barn::patch)
     })
     .bind("0.0.0.0:9070")?
     .run()
@@ -0,0 +1,137 @@
+use caseless::default_case_fold_str;
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+/// Transforms the string values before they are used as index keys. The same normalizer
+/// is applied to the values of the resources and to the values being looked up.
+pub trait Normalizer: Send + Sync {
+    fn normalize(&self, s: &str) -> String;
+
+    /// Normalizes a prefix so that the normalized values starting with it are the values starting with
+    /// the prefix. Trailing whitespace is kept, "acme " does not match "Acmeist".
+    fn normalize_prefix(&self, s: &str) -> String {
+        self.normalize(s)
+    }
+}
+
+/// Keeps the value as is, lookups are case sensitive
+pub struct Identity;
+
+/// Trims and lowercases the value, the default
+pub struct Lowercase;
+
+/// Unicode NFKC normalization followed by full case folding
+pub struct NfkcCasefold;
+
+/// Removes the diacritical marks, e.g. é becomes e
+pub struct StripAccents;
+
+/// Trims and replaces every run of whitespace with a single space
+pub struct CollapseWhitespace;
+
+/// Applies the normalizers in sequence
+pub struct Chain(Vec<Box<dyn Normalizer>>);
+
+impl Normalizer for Identity {
+    fn normalize(&self, s: &str) -> String {
+        String::from(s)
+    }
+}
+
+impl Normalizer for Lowercase {
+    fn normalize(&self, s: &str) -> String {
+        s.trim().to_lowercase()
+    }
+
+    fn normalize_prefix(&self, s: &str) -> String {
+        s.trim_start().to_lowercase()
+    }
+}
+
+impl Normalizer for NfkcCasefold {
+    fn normalize(&self, s: &str) -> String {
+        default_case_fold_str(&s.nfkc().collect::<String>()).nfkc().collect()
+    }
+}
+
+impl Normalizer for StripAccents {
+    fn normalize(&self, s: &str) -> String {
+        s.nfd().filter(|c| !is_combining_mark(*c)).nfc().collect()
+    }
+}
+
+impl Normalizer for CollapseWhitespace {
+    fn normalize(&self, s: &str) -> String {
+        s.split_whitespace().collect::<Vec<&str>>().join(" ")
+    }
+
+    fn normalize_prefix(&self, s: &str) -> String {
+        let mut normalized = self.normalize(s);
+        if !normalized.is_empty() && s.ends_with(char::is_whitespace) {
+            normalized.push(' ');
+        }
+        normalized
+    }
+}
+
+impl Normalizer for Chain {
+    fn normalize(&self, s: &str) -> String {
+        let mut normalized = String::from(s);
+        for n in &self.0 {
+            normalized = n.normalize(&normalized);
+        }
+        normalized
+    }
+
+    fn normalize_prefix(&self, s: &str) -> String {
+        let mut normalized = String::from(s);
+        for n in &self.0 {
+            normalized = n.normalize_prefix(&normalized);
+        }
+        normalized
+    }
+}
+
+/// Returns the built-in normalizer with the given name, names separated by commas
+/// are chained in the given order. An empty name selects the default lowercase normalizer.
+pub fn from_name(name: &str) -> Option<Box<dyn Normalizer>> {
+    let names: Vec<&str> = name.split(',').map(|n| n.trim()).collect();
+    if names.len() > 1 {
+        let mut chain = vec!();
+        for n in names {
+            chain.push(from_name(n)?);
+        }
+        return Some(Box::new(Chain(chain)));
+    }
+
+    match names[0] {
+        "" | "lowercase" => Some(Box::new(Lowercase)),
+        "none" => Some(Box::new(Identity)),
+        "nfkc_casefold" => Some(Box::new(NfkcCasefold)),
+        "strip_accents" => Some(Box::new(StripAccents)),
+        "collapse_whitespace" => Some(Box::new(CollapseWhitespace)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalizers() {
+        let normalize = |name: &str, s: &str| from_name(name).unwrap().normalize(s);
+        assert_eq!("Acme", normalize("none", "Acme"));
+        assert_eq!("acme  inc", normalize("", " Acme  Inc "));
+        assert_eq!("strasse fi", normalize("nfkc_casefold", "STRAßE ﬁ"));
+        assert_eq!("Cafe Creme", normalize("strip_accents", "Café Crème"));
+        assert_eq!("Acme Inc", normalize("collapse_whitespace", " Acme \t Inc\n"));
+        assert_eq!("cafe creme", normalize("strip_accents, collapse_whitespace, lowercase", " Café   Crème "));
+        let normalize_prefix = |name: &str, s: &str| from_name(name).unwrap().normalize_prefix(s);
+        assert_eq!("acme ", normalize_prefix("", " Acme "));
+        assert_eq!("Acme Inc ", normalize_prefix("collapse_whitespace", " Acme \t Inc\n"));
+        assert_eq!("cafe ", normalize_prefix("strip_accents, collapse_whitespace, lowercase", " Café   "));
+        assert!(from_name("soundex").is_none());
+        assert!(from_name("lowercase,soundex").is_none());
+    }
+}
@@ -0,0 +1,494 @@
+use std::cmp::Ordering;
+
+use serde_json::{Map, Value};
+use log::debug;
+
+use crate::normalizer::{Lowercase, Normalizer};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    /// the string value starts with the given prefix, both normalized by the normalizer of the attribute's
+    /// index or by the default lowercase normalizer
+    StartsWith
+}
+
+/// A single `@.attr <op> literal` term of a JSONPath filter expression or a condition given
+/// explicitly to the search. The attribute path is converted to a JSON pointer to match
+/// the `at_path` of indices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub at_path: String,
+    pub op: Op,
+    pub value: Value
+}
+
+/// The input to `Barn::search`. A record matches if it satisfies both the JSONPath filter
+/// and all the conditions. Equality conditions on an indexed attribute are evaluated using the index's
+/// key encoding, e.g. strings are compared after trimming and lowercasing. Ranges on indexed dates
+/// compare the instants, ranges on other strings compare the stored values.
+#[derive(Debug, Default)]
+pub struct Query {
+    pub filter: Option<String>,
+    pub conditions: Vec<Condition>,
+    /// maximum number of resources to be returned
+    pub limit: Option<usize>,
+    /// number of matching resources to skip
+    pub offset: usize,
+    /// position of the last resource returned in the previous page
+    pub after: Option<Cursor>,
+    /// the sort attribute must be indexed, resources missing from its index follow the others in the order of PKs
+    pub sort: Option<Sort>,
+    /// JSON pointers of the attributes to be returned, all attributes are returned if empty
+    pub fields: Vec<String>
+}
+
+/// Position of a resource in the order the search visits the resources
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    /// key of the index entry the resource was found under, None when visited in the order of PKs
+    pub key: Option<Vec<u8>>,
+    pub pk: u64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sort {
+    pub at_path: String,
+    pub descending: bool
+}
+
+impl Sort {
+    pub fn asc(attr_path: &str) -> Sort {
+        Sort {
+            at_path: to_pointer(attr_path),
+            descending: false
+        }
+    }
+
+    pub fn desc(attr_path: &str) -> Sort {
+        Sort {
+            at_path: to_pointer(attr_path),
+            descending: true
+        }
+    }
+}
+
+impl Query {
+    pub fn new(filter: &str) -> Query {
+        Query {
+            filter: Some(String::from(filter)),
+            ..Default::default()
+        }
+    }
+}
+
+impl Condition {
+    pub fn new(attr_path: &str, op: Op, value: Value) -> Condition {
+        Condition {
+            at_path: to_pointer(attr_path),
+            op,
+            value
+        }
+    }
+
+    pub fn eq(attr_path: &str, value: Value) -> Condition {
+        Condition::new(attr_path, Op::Eq, value)
+    }
+
+    pub fn gt(attr_path: &str, value: Value) -> Condition {
+        Condition::new(attr_path, Op::Gt, value)
+    }
+
+    pub fn ge(attr_path: &str, value: Value) -> Condition {
+        Condition::new(attr_path, Op::Ge, value)
+    }
+
+    pub fn lt(attr_path: &str, value: Value) -> Condition {
+        Condition::new(attr_path, Op::Lt, value)
+    }
+
+    pub fn le(attr_path: &str, value: Value) -> Condition {
+        Condition::new(attr_path, Op::Le, value)
+    }
+
+    pub fn starts_with(attr_path: &str, prefix: &str) -> Condition {
+        Condition::new(attr_path, Op::StartsWith, Value::from(prefix))
+    }
+
+    /// Inclusive range on both ends
+    pub fn between(attr_path: &str, lower: Value, upper: Value) -> Vec<Condition> {
+        vec!(Condition::ge(attr_path, lower), Condition::le(attr_path, upper))
+    }
+
+    /// Array attributes match when any of their elements matches, missing attributes are equal to null
+    pub fn matches(&self, r: &Value) -> bool {
+        match r.pointer(&self.at_path) {
+            Some(Value::Array(elements)) => elements.iter().any(|e| self.matches_value(e)),
+            Some(v) => self.matches_value(v),
+            None => self.op == Op::Eq && self.value.is_null()
+        }
+    }
+
+    fn matches_value(&self, v: &Value) -> bool {
+        if self.op == Op::StartsWith {
+            return match (v, &self.value) {
+                (Value::String(s), Value::String(p)) => Lowercase.normalize(s).starts_with(&Lowercase.normalize_prefix(p)),
+                _ => false
+            };
+        }
+
+        let ord = compare(v, &self.value);
+        if ord.is_none() {
+            return false;
+        }
+
+        self.op.accepts(ord.unwrap())
+    }
+}
+
+impl Op {
+    /// Returns true if a value comparing to the condition's value with the given ordering satisfies the operator
+    pub fn accepts(&self, ord: Ordering) -> bool {
+        match self {
+            Op::Eq => ord == Ordering::Equal,
+            Op::Gt => ord == Ordering::Greater,
+            Op::Ge => ord != Ordering::Less,
+            Op::Lt => ord == Ordering::Less,
+            Op::Le => ord != Ordering::Greater,
+            Op::StartsWith => false
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AggregateOp {
+    Count,
+    Min,
+    Max,
+    Sum,
+    Avg
+}
+
+impl AggregateOp {
+    pub fn from_name(name: &str) -> Option<AggregateOp> {
+        match name {
+            "count" => Some(AggregateOp::Count),
+            "min" => Some(AggregateOp::Min),
+            "max" => Some(AggregateOp::Max),
+            "sum" => Some(AggregateOp::Sum),
+            "avg" => Some(AggregateOp::Avg),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AggregateOp::Count => "count",
+            AggregateOp::Min => "min",
+            AggregateOp::Max => "max",
+            AggregateOp::Sum => "sum",
+            AggregateOp::Avg => "avg"
+        }
+    }
+}
+
+/// An aggregation over the resources matching a `Query`. Except for counting, the aggregated
+/// attribute is required and only its numeric values are considered.
+#[derive(Debug, Clone)]
+pub struct Aggregate {
+    pub op: AggregateOp,
+    pub at_path: Option<String>,
+    pub group_by: Option<String>
+}
+
+impl Aggregate {
+    pub fn count() -> Aggregate {
+        Aggregate {
+            op: AggregateOp::Count,
+            at_path: None,
+            group_by: None
+        }
+    }
+
+    pub fn new(op: AggregateOp, attr_path: &str) -> Aggregate {
+        Aggregate {
+            op,
+            at_path: Some(to_pointer(attr_path)),
+            group_by: None
+        }
+    }
+
+    pub fn group_by(mut self, attr_path: &str) -> Aggregate {
+        self.group_by = Some(to_pointer(attr_path));
+        self
+    }
+}
+
+/// Converts a dotted attribute path to a JSON pointer, pointers are returned as is.
+pub fn to_pointer(attr_path: &str) -> String {
+    if attr_path.starts_with('/') {
+        return String::from(attr_path);
+    }
+    format!("/{}", attr_path.replace(".", "/"))
+}
+
+/// Returns a copy of the given resource containing only the attributes at the given JSON pointers.
+/// The nesting of the attributes is retained, arrays are always copied whole.
+pub fn project(r: &Value, fields: &[String]) -> Value {
+    let mut projected = Value::Object(Map::new());
+    for f in fields {
+        if r.pointer(f).is_none() {
+            continue;
+        }
+        let segments: Vec<String> = f.split('/').skip(1).map(|s| s.replace("~1", "/").replace("~0", "~")).collect();
+        copy_at(r, &mut projected, &segments);
+    }
+    projected
+}
+
+fn copy_at(src: &Value, dst: &mut Value, segments: &[String]) {
+    if segments.is_empty() {
+        *dst = src.clone();
+        return;
+    }
+
+    if let Value::Object(src_obj) = src {
+        if let Some(child) = src_obj.get(&segments[0]) {
+            if !dst.is_object() {
+                *dst = Value::Object(Map::new());
+            }
+            let dst_child = dst.as_object_mut().unwrap().entry(segments[0].clone()).or_insert(Value::Null);
+            copy_at(child, dst_child, &segments[1..]);
+        }
+    }
+    else if src.is_array() {
+        *dst = src.clone();
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64().unwrap().partial_cmp(&y.as_f64().unwrap()),
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        (Value::Bool(x), Value::Bool(y)) => Some(x.cmp(y)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        _ => None
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Path(String),
+    Literal(Value),
+    Op(String),
+    And,
+    Unknown
+}
+
+/// Extracts the conditions that must all hold for a record to match the given filter
+/// expression of the form `$[?(@.a == 'x' && @.b == 1)]`. Returns an empty list if the
+/// expression is not a plain conjunction, in which case the caller must scan all the records.
+/// Terms using an unrecognised operator are skipped, these are still evaluated by the JSONPath.
+pub fn parse_conditions(expr: &str) -> Vec<Condition> {
+    let mut conditions = vec!();
+    let expr = expr.trim();
+    let filter = expr.strip_prefix("$[?(").and_then(|e| e.strip_suffix(")]"));
+    if filter.is_none() {
+        return conditions;
+    }
+
+    let tokens = tokenize(filter.unwrap());
+    if tokens.is_none() {
+        debug!("unable to extract conditions from the filter {}", expr);
+        return conditions;
+    }
+
+    let tokens = tokens.unwrap();
+    for term in tokens.split(|t| *t == Token::And) {
+        match term {
+            [Token::Path(p), Token::Op(o), Token::Literal(v)] => {
+                if let Some(op) = to_op(o, false) {
+                    conditions.push(Condition { at_path: p.clone(), op, value: v.clone() });
+                }
+            },
+            [Token::Literal(v), Token::Op(o), Token::Path(p)] => {
+                if let Some(op) = to_op(o, true) {
+                    conditions.push(Condition { at_path: p.clone(), op, value: v.clone() });
+                }
+            },
+            _ => {
+                debug!("unsupported term in the filter {}", expr);
+                return vec!();
+            }
+        }
+    }
+
+    conditions
+}
+
+/// flipped is true when the literal appears on the left hand side of the operator
+fn to_op(o: &str, flipped: bool) -> Option<Op> {
+    match (o, flipped) {
+        ("==", _) => Some(Op::Eq),
+        (">", false) | ("<", true) => Some(Op::Gt),
+        (">=", false) | ("<=", true) => Some(Op::Ge),
+        ("<", false) | (">", true) => Some(Op::Lt),
+        ("<=", false) | (">=", true) => Some(Op::Le),
+        _ => None
+    }
+}
+
+fn tokenize(filter: &str) -> Option<Vec<Token>> {
+    let chars: Vec<char> = filter.chars().collect();
+    let mut tokens = vec!();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+            continue;
+        }
+
+        let start = pos;
+        let token;
+        match c {
+            '@' => {
+                pos += 1;
+                let mut at_path = String::new();
+                while pos < chars.len() && chars[pos] == '.' {
+                    pos += 1;
+                    let name_start = pos;
+                    while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_' || chars[pos] == '-') {
+                        pos += 1;
+                    }
+                    if name_start == pos {
+                        return None;
+                    }
+                    at_path.push('/');
+                    at_path.extend(&chars[name_start..pos]);
+                }
+                if at_path.is_empty() {
+                    return None;
+                }
+                token = Token::Path(at_path);
+            },
+            '\'' | '"' => {
+                pos += 1;
+                while pos < chars.len() && chars[pos] != c {
+                    // escaped quotes are not worth the trouble, leave them to the JSONPath
+                    if chars[pos] == '\\' {
+                        return None;
+                    }
+                    pos += 1;
+                }
+                if pos == chars.len() {
+                    return None;
+                }
+                let s: String = chars[start + 1..pos].iter().collect();
+                pos += 1;
+                token = Token::Literal(Value::from(s));
+            },
+            '=' | '!' | '<' | '>' => {
+                pos += 1;
+                if pos < chars.len() && chars[pos] == '=' {
+                    pos += 1;
+                }
+                token = Token::Op(chars[start..pos].iter().collect());
+            },
+            '&' => {
+                if pos + 1 < chars.len() && chars[pos + 1] == '&' {
+                    pos += 2;
+                    token = Token::And;
+                }
+                else {
+                    return None;
+                }
+            },
+            _ => {
+                while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '.' || chars[pos] == '-' || chars[pos] == '+') {
+                    pos += 1;
+                }
+                if start == pos {
+                    // parentheses, || and anything else that can't be planned
+                    token = Token::Unknown;
+                    pos += 1;
+                }
+                else {
+                    let word: String = chars[start..pos].iter().collect();
+                    match serde_json::from_str::<Value>(word.as_str()) {
+                        Ok(v) => token = Token::Literal(v),
+                        Err(_) => token = Token::Unknown
+                    }
+                }
+            }
+        }
+
+        if token == Token::Unknown {
+            return None;
+        }
+        tokens.push(token);
+    }
+
+    Some(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_conditions() {
+        let conditions = parse_conditions("$[?(@.reg_id == 'id1' && 1 == @.account_id && @.location.lat == 2.5)]");
+        assert_eq!(3, conditions.len());
+        assert_eq!(Condition { at_path: String::from("/reg_id"), op: Op::Eq, value: json!("id1") }, conditions[0]);
+        assert_eq!(Condition { at_path: String::from("/account_id"), op: Op::Eq, value: json!(1) }, conditions[1]);
+        assert_eq!(Condition { at_path: String::from("/location/lat"), op: Op::Eq, value: json!(2.5) }, conditions[2]);
+
+        let conditions = parse_conditions("$[?(@.created_at >= 1603171057000 && 1603171057005 > @.created_at)]");
+        assert_eq!(vec!(Condition::ge("created_at", json!(1603171057000_i64)), Condition::lt("created_at", json!(1603171057005_i64))), conditions);
+
+        let conditions = parse_conditions("$[?(@.approved == true && @.website != \"x\")]");
+        assert_eq!(vec!(Condition { at_path: String::from("/approved"), op: Op::Eq, value: json!(true) }), conditions);
+
+        assert!(parse_conditions("$[?(@.reg_id == 'id1' || @.reg_id == 'id2')]").is_empty());
+        assert!(parse_conditions("$[?((@.reg_id == 'id1'))]").is_empty());
+        assert!(parse_conditions("$.reg_id").is_empty());
+        assert!(parse_conditions("$[?(@.reg_id == 'it\\'s')]").is_empty());
+    }
+
+    #[test]
+    fn test_condition_matches() {
+        let r = json!({"a": {"b": 5}, "s": "acme", "f": 2.5});
+        assert!(Condition::eq("a.b", json!(5)).matches(&r));
+        assert!(Condition::eq("/a/b", json!(5.0)).matches(&r));
+        assert!(Condition::gt("a.b", json!(4)).matches(&r));
+        assert!(!Condition::gt("a.b", json!(5)).matches(&r));
+        assert!(Condition::le("f", json!(2.5)).matches(&r));
+        assert!(Condition::between("s", json!("ac"), json!("ad")).iter().all(|c| c.matches(&r)));
+        assert!(!Condition::lt("s", json!(1)).matches(&r));
+        assert!(!Condition::eq("missing", json!(1)).matches(&r));
+        assert!(Condition::eq("missing", json!(null)).matches(&r));
+        assert!(Condition::starts_with("s", "AC").matches(&r));
+        assert!(!Condition::starts_with("s", "acmes").matches(&r));
+        assert!(!Condition::starts_with("a/b", "5").matches(&r));
+
+        let r = json!({"tags": ["a", "c"], "loc": [12.5, 77.5]});
+        assert!(Condition::eq("tags", json!("c")).matches(&r));
+        assert!(!Condition::eq("tags", json!("b")).matches(&r));
+        assert!(Condition::gt("loc", json!(70)).matches(&r));
+        assert!(!Condition::lt("loc", json!(10)).matches(&r));
+    }
+
+    #[test]
+    fn test_project() {
+        let r = json!({"id": "1", "a": {"b": 5, "c": 6}, "loc": [1.0, 2.0], "s": "acme"});
+        let fields = vec!(String::from("/id"), to_pointer("a.c"), String::from("/loc/1"), String::from("/missing"));
+        assert_eq!(json!({"id": "1", "a": {"c": 6}, "loc": [1.0, 2.0]}), project(&r, &fields));
+        assert_eq!(json!({}), project(&r, &vec!(String::from("/s/x"))));
+    }
+}
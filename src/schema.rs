@@ -1,6 +1,7 @@
 use serde_json::{json, Value, Map};
 use log::{info, warn, trace, debug};
 use crate::errors::BarnError;
+use std::convert::TryInto;
 
 pub fn get_res_names(sc: &Value) -> Option<Vec<String>> {
     let obj: &Map<String, Value> = sc.as_object().unwrap();
@@ -30,7 +31,35 @@ pub fn parse_datetime(s: &str) -> Result<Vec<u8>, BarnError> {
         return Err(BarnError::InvalidAttributeValueError);
     }
 
-    return Ok(dt.unwrap().timestamp_millis().to_le_bytes().to_vec());
+    return Ok(i64_to_key(dt.unwrap().timestamp_millis()));
+}
+
+/// Encodes the integer in big-endian order with the sign bit flipped so that
+/// the byte-wise ordering of the keys matches the numeric ordering.
+pub fn i64_to_key(i: i64) -> Vec<u8> {
+    ((i as u64) ^ (1 << 63)).to_be_bytes().to_vec()
+}
+
+pub fn key_to_i64(key: &[u8]) -> Option<i64> {
+    let bytes: [u8; 8] = key.try_into().ok()?;
+    Some((u64::from_be_bytes(bytes) ^ (1 << 63)) as i64)
+}
+
+/// Encodes the float such that the byte-wise ordering of the keys follows the IEEE 754
+/// total order, the sign bit is flipped for positive values and all bits for negative values.
+pub fn f64_to_key(f: f64) -> Vec<u8> {
+    // -0.0 and 0.0 must map to the same key
+    let f = if f == 0.0 { 0.0 } else { f };
+    let bits = f.to_bits();
+    let key = if bits & (1 << 63) == 0 { bits ^ (1 << 63) } else { !bits };
+    key.to_be_bytes().to_vec()
+}
+
+pub fn key_to_f64(key: &[u8]) -> Option<f64> {
+    let bytes: [u8; 8] = key.try_into().ok()?;
+    let key = u64::from_be_bytes(bytes);
+    let bits = if key & (1 << 63) != 0 { key ^ (1 << 63) } else { !key };
+    Some(f64::from_bits(bits))
 }
 
 #[cfg(test)]
@@ -48,6 +77,32 @@ mod tests {
         assert_eq!(None, res_names);
     }
 
+    #[test]
+    fn test_key_ordering() {
+        let ints = vec!(i64::MIN, -1000, -1, 0, 1, 255, 256, 1603171057000, i64::MAX);
+        for w in ints.windows(2) {
+            assert!(i64_to_key(w[0]) < i64_to_key(w[1]), "{} {}", w[0], w[1]);
+        }
+
+        let floats = vec!(f64::NEG_INFINITY, -1.5e10, -2.0, -1.0, -0.5, 0.0, 0.25, 1.0, 2.0, 1.5e10, f64::INFINITY);
+        for w in floats.windows(2) {
+            assert!(f64_to_key(w[0]) < f64_to_key(w[1]), "{} {}", w[0], w[1]);
+        }
+        assert_eq!(f64_to_key(-0.0), f64_to_key(0.0));
+
+        for i in ints {
+            assert_eq!(Some(i), key_to_i64(&i64_to_key(i)));
+        }
+        for f in floats {
+            assert_eq!(Some(f), key_to_f64(&f64_to_key(f)));
+        }
+        assert_eq!(None, key_to_i64(b"abc"));
+
+        let earlier = parse_datetime("2021-01-16T18:36:14+01:00").unwrap();
+        let later = parse_datetime("2021-01-16T18:36:14Z").unwrap();
+        assert!(earlier < later);
+    }
+
     #[test]
     fn test_chrono_utc_parsing() {
         let val = "2021-01-16T18:36:14+01:00";
@@ -1,29 +1,77 @@
 use std::fs;
-use serde_json::{Value, from_str};
+use std::sync::mpsc::channel;
+use serde_json::{json, Value, from_str};
 use barn;
 use barn::errors::BarnError;
+use barn::{Precondition, VersionMatch};
+use barn::conf::{AttrPath, DbConf, IdStrategy, IndexConf, MetadataConf};
+use barn::query::{Aggregate, AggregateOp, Condition, Cursor, Query, Sort};
 
-#[test]
-fn test_insert() {
-    let env_dir = String::from("/tmp/barn");
+fn open_barn(env_dir: &str) -> barn::Barn {
+    open_barn_with(env_dir, |_| {})
+}
 
+fn open_barn_with<F: FnOnce(&mut DbConf)>(env_dir: &str, configure: F) -> barn::Barn {
     // cleanup
-    fs::remove_dir_all(env_dir.clone());
+    let _ = fs::remove_dir_all(env_dir);
 
     let schema_file = fs::File::open("config/schema.json").unwrap();
-    let schema: Value = serde_json::from_reader(schema_file).unwrap();
     let db_conf_file = fs::File::open("config/db-conf.json").unwrap();
-    let db_conf = serde_json::from_reader(db_conf_file).unwrap();
+    let mut db_conf = serde_json::from_reader(db_conf_file).unwrap();
+    configure(&mut db_conf);
 
-    let barn = barn::Barn::open(&env_dir, &db_conf, &schema).unwrap();
-    let mut record = from_str(fs::read_to_string("config/samples/business.json").expect("json record file not found").as_str()).unwrap();
-    barn.insert(String::from("Business"), &mut record).unwrap();
+    barn::Barn::open(env_dir, &db_conf, schema_file).unwrap()
+}
+
+/// Keeps the client supplied timestamps, needed for searching on created_at
+fn without_metadata(db_conf: &mut DbConf) {
+    db_conf.resources.get_mut("Business").unwrap().metadata = MetadataConf::default();
+}
+
+fn search(barn: &barn::Barn, expr: &str) -> Vec<Value> {
+    search_with(barn, Query::new(expr))
+}
+
+fn search_with(barn: &barn::Barn, q: Query) -> Vec<Value> {
+    search_page(barn, q).0
+}
+
+fn search_page(barn: &barn::Barn, q: Query) -> (Vec<Value>, Option<Cursor>) {
+    let (sn, rc) = channel();
+    let after = barn.search(String::from("Business"), q, sn).unwrap();
+    let mut buf = vec!();
+    for chunk in rc {
+        buf.extend_from_slice(&chunk.unwrap());
+    }
+    let results: Value = serde_json::from_slice(&buf).unwrap();
+    (results.as_array().unwrap().clone(), after)
+}
+
+/// The reg_id of each result in the order of the results
+fn reg_ids(results: Vec<Value>) -> Vec<String> {
+    results.iter().map(|r| String::from(r["reg_id"].as_str().unwrap())).collect()
+}
+
+fn search_reg_ids(barn: &barn::Barn, conditions: Vec<Condition>) -> Vec<String> {
+    reg_ids(search_with(barn, Query { filter: None, conditions, ..Default::default() }))
+}
+
+fn read_sample() -> Value {
+    from_str(fs::read_to_string("config/samples/business.json").expect("json record file not found").as_str()).unwrap()
+}
+
+#[test]
+fn test_insert() {
+    let barn = open_barn("/tmp/barn");
+    let mut record = read_sample();
+    let pk = barn.insert(String::from("Business"), &mut record).unwrap();
+    assert_eq!(1, pk);
     let id = record.as_object().unwrap().get("id").unwrap();
     assert_eq!("1", id.as_str().unwrap());
 
     let dup_rg_id_result = barn.insert(String::from("Business"), &mut record);
     match dup_rg_id_result {
-        Ok(()) => {
+        Ok(_) => {
             assert!(false);
         },
         Err(e) => {
@@ -35,3 +83,1076 @@ fn test_insert() {
     let mut get_record = barn.get(1, String::from("Business")).unwrap();
     assert_eq!("1", get_record.as_object_mut().unwrap().get_mut("id").unwrap().as_str().unwrap());
 }
+
+#[test]
+fn test_update() {
+    let barn = open_barn("/tmp/barn_update");
+    let mut first = read_sample();
+    barn.insert(String::from("Business"), &mut first).unwrap();
+    let mut second = read_sample();
+    second["reg_id"] = Value::from("id2");
+    barn.insert(String::from("Business"), &mut second).unwrap();
+
+    // move the first resource to a new reg_id, this must free up the old value
+    let mut changed = barn.get(1, String::from("Business")).unwrap();
+    changed["reg_id"] = Value::from("id3");
+    changed["website"] = Value::from("https://example.com");
+    barn.update(String::from("Business"), 1, &mut changed, &Precondition::default()).unwrap();
+
+    let updated = barn.get(1, String::from("Business")).unwrap();
+    assert_eq!("id3", updated["reg_id"].as_str().unwrap());
+    assert_eq!("https://example.com", updated["website"].as_str().unwrap());
+    assert_eq!("1", updated["id"].as_str().unwrap());
+
+    let mut third = read_sample();
+    barn.insert(String::from("Business"), &mut third).unwrap();
+
+    // unique constraint must still be enforced on the changed attribute
+    let mut conflicting = barn.get(1, String::from("Business")).unwrap();
+    conflicting["reg_id"] = Value::from("id2");
+    let conflict_result = barn.update(String::from("Business"), 1, &mut conflicting, &Precondition::default());
+    assert!(conflict_result.is_err());
+    assert_eq!("id3", barn.get(1, String::from("Business")).unwrap()["reg_id"].as_str().unwrap());
+
+    let missing_result = barn.update(String::from("Business"), 100, &mut conflicting, &Precondition::default());
+    match missing_result {
+        Err(BarnError::ResourceNotFoundError) => {
+            assert!(true);
+        },
+        _ => {
+            assert!(false);
+        }
+    }
+}
+
+#[test]
+fn test_delete() {
+    let barn = open_barn("/tmp/barn_delete");
+    let mut first = read_sample();
+    barn.insert(String::from("Business"), &mut first).unwrap();
+    let mut second = read_sample();
+    second["reg_id"] = Value::from("id2");
+    barn.insert(String::from("Business"), &mut second).unwrap();
+
+    let deleted = barn.delete(String::from("Business"), 1, &Precondition::default()).unwrap();
+    assert_eq!("id1", deleted["reg_id"].as_str().unwrap());
+    assert!(barn.get(1, String::from("Business")).is_err());
+    assert!(barn.delete(String::from("Business"), 1, &Precondition::default()).is_err());
+
+    // the unique index entry must be purged, so the same reg_id can be inserted again
+    let mut third = read_sample();
+    barn.insert(String::from("Business"), &mut third).unwrap();
+    assert_eq!("3", third["id"].as_str().unwrap());
+
+    // the other resource sharing the non-unique display_name index is left untouched
+    let remaining = barn.get(2, String::from("Business")).unwrap();
+    assert_eq!("id2", remaining["reg_id"].as_str().unwrap());
+}
+
+#[test]
+fn test_patch() {
+    let barn = open_barn("/tmp/barn_patch");
+    let mut first = read_sample();
+    barn.insert(String::from("Business"), &mut first).unwrap();
+
+    let merge_doc = serde_json::json!({"approved": true, "website": "https://example.com", "id": "100"});
+    let (patched, version) = barn.patch(String::from("Business"), 1, &Precondition::default(), |r| {
+        json_patch::merge(r, &merge_doc);
+        Ok(())
+    }).unwrap();
+    assert_eq!(2, version);
+    assert_eq!(true, patched["approved"].as_bool().unwrap());
+    assert_eq!("1", patched["id"].as_str().unwrap());
+
+    let ops = json_patch::from_value(serde_json::json!([{"op": "replace", "path": "/reg_id", "value": "id2"}])).unwrap();
+    barn.patch(String::from("Business"), 1, &Precondition::default(), |r| {
+        json_patch::patch(r, &ops).map_err(|_| BarnError::InvalidPatchError)
+    }).unwrap();
+    let stored = barn.get(1, String::from("Business")).unwrap();
+    assert_eq!("id2", stored["reg_id"].as_str().unwrap());
+    assert_eq!("https://example.com", stored["website"].as_str().unwrap());
+
+    // a failing function must leave the stored resource untouched
+    let failed = barn.patch(String::from("Business"), 1, &Precondition::default(), |r| {
+        r["reg_id"] = Value::from("id3");
+        Err(BarnError::InvalidResourceError)
+    });
+    assert!(failed.is_err());
+    assert_eq!("id2", barn.get(1, String::from("Business")).unwrap()["reg_id"].as_str().unwrap());
+
+    // old reg_id was released from the unique index
+    let mut second = read_sample();
+    barn.insert(String::from("Business"), &mut second).unwrap();
+}
+
+#[test]
+fn test_search_with_index() {
+    let barn = open_barn("/tmp/barn_search_index");
+    for (reg_id, display_name) in vec!(("id1", "Acme"), ("id2", "acme"), ("id3", "Other")) {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["display_name"] = Value::from(display_name);
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+
+    // unique index
+    let results = search(&barn, "$[?(@.reg_id == 'id2')]");
+    assert_eq!(1, results.len());
+    assert_eq!("2", results[0]["id"].as_str().unwrap());
+
+    // the non-unique index is case insensitive but the filter is still applied on the candidates
+    let results = search(&barn, "$[?(@.display_name == 'Acme' && @.approved == false)]");
+    assert_eq!(1, results.len());
+    assert_eq!("id1", results[0]["reg_id"].as_str().unwrap());
+
+    let results = search(&barn, "$[?(@.display_name == 'Missing')]");
+    assert!(results.is_empty());
+
+    // ranges on strings compare the values as stored, the normalized keys of the index are not in the same order
+    assert_eq!(vec!("id1", "id3"), search_reg_ids(&barn, vec!(Condition::lt("display_name", Value::from("a")))));
+    let q = Query { filter: None, conditions: vec!(Condition::lt("display_name", Value::from("a")), Condition::eq("reg_id", Value::from("id1"))), ..Default::default() };
+    assert_eq!(1, search_with(&barn, q).len());
+
+    // falls back to a scan for non-indexed attributes and unsupported operators
+    let results = search(&barn, "$[?(@.country_code == 'IN')]");
+    assert_eq!(3, results.len());
+    let results = search(&barn, "$[?(@.reg_id != 'id1')]");
+    assert_eq!(2, results.len());
+}
+
+#[test]
+fn test_range_search() {
+    let barn = open_barn_with("/tmp/barn_range", without_metadata);
+    // inserted out of order to make sure the results follow the index order
+    for (reg_id, created_at) in vec!(("id1", 1603171057300_i64), ("id2", 1603171057100), ("id3", 1603171057200), ("id4", 1603171057400)) {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["created_at"] = Value::from(created_at);
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+
+    let q = Query { filter: None, conditions: Condition::between("created_at", Value::from(1603171057100_i64), Value::from(1603171057300_i64)), ..Default::default() };
+    assert_eq!(vec!("id2", "id3", "id1"), reg_ids(search_with(&barn, q)));
+
+    let q = Query { filter: None, conditions: vec!(Condition::gt("created_at", Value::from(1603171057200_i64))), ..Default::default() };
+    assert_eq!(vec!("id1", "id4"), reg_ids(search_with(&barn, q)));
+
+    let q = Query { filter: None, conditions: vec!(Condition::lt("created_at", Value::from(1603171057200_i64))), ..Default::default() };
+    assert_eq!(vec!("id2"), reg_ids(search_with(&barn, q)));
+
+    // combined with a JSONPath filter and a condition on a non-indexed attribute
+    let q = Query { filter: Some(String::from("$[?(@.reg_id != 'id3')]")), conditions: vec!(Condition::le("created_at", Value::from(1603171057300_i64)), Condition::eq("country_code", Value::from("IN"))), ..Default::default() };
+    assert_eq!(vec!("id2", "id1"), reg_ids(search_with(&barn, q)));
+
+    // range terms in the JSONPath filter are also served by the index
+    assert_eq!(vec!("id3", "id1"), reg_ids(search(&barn, "$[?(@.created_at >= 1603171057200 && @.created_at < 1603171057400)]")));
+
+    let q = Query { filter: None, conditions: vec!(Condition::gt("created_at", Value::from("not a number"))), ..Default::default() };
+    let (sn, _rc) = channel();
+    assert!(barn.search(String::from("Business"), q, sn).is_err());
+}
+
+#[test]
+fn test_date_time_range_search() {
+    let barn = open_barn_with("/tmp/barn_date_time_range", |db_conf| {
+        let indices = &mut db_conf.resources.get_mut("Business").unwrap().indices;
+        indices.push(IndexConf { attr_path: AttrPath::Single(String::from("registered_at")), unique: Some(false), index_missing: None, normalizer: None });
+    });
+    // 13:00Z, 14:00Z and 12:00Z, the offsets make the strings sort differently from the instants
+    for (reg_id, registered_at) in vec!(("id1", "2021-01-16T18:00:00+05:00"), ("id2", "2021-01-16T14:00:00Z"), ("id3", "2021-01-16T07:00:00-05:00")) {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["registered_at"] = Value::from(registered_at);
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+
+    assert_eq!(vec!("id3", "id1"), search_reg_ids(&barn, vec!(Condition::lt("registered_at", Value::from("2021-01-16T13:30:00Z")))));
+    assert_eq!(vec!("id1", "id2"), search_reg_ids(&barn, Condition::between("registered_at", Value::from("2021-01-16T08:00:00-05:00"), Value::from("2021-01-16T14:00:00Z"))));
+    assert_eq!(vec!("id2"), search_reg_ids(&barn, vec!(Condition::eq("registered_at", Value::from("2021-01-16T19:00:00+05:00")))));
+    // checked on the resource found using another index
+    assert_eq!(vec!("id1"), search_reg_ids(&barn, vec!(Condition::eq("reg_id", Value::from("id1")), Condition::lt("registered_at", Value::from("2021-01-16T13:30:00Z")))));
+    assert!(search_reg_ids(&barn, vec!(Condition::eq("reg_id", Value::from("id2")), Condition::lt("registered_at", Value::from("2021-01-16T13:30:00Z")))).is_empty());
+}
+
+#[test]
+fn test_paginated_search() {
+    let barn = open_barn("/tmp/barn_paginated");
+    for i in 1..=7 {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(format!("id{}", i));
+        r["display_name"] = Value::from(if i % 2 == 0 { "even" } else { "odd" });
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+
+    let ids = |results: &Vec<Value>| -> Vec<String> {
+        results.iter().map(|r| String::from(r["id"].as_str().unwrap())).collect()
+    };
+
+    // full scan
+    let (page, after) = search_page(&barn, Query { limit: Some(3), ..Default::default() });
+    assert_eq!(vec!("1", "2", "3"), ids(&page));
+    assert_eq!(Some(Cursor { key: None, pk: 3 }), after);
+    let (page, after) = search_page(&barn, Query { limit: Some(3), after, ..Default::default() });
+    assert_eq!(vec!("4", "5", "6"), ids(&page));
+    let (page, after) = search_page(&barn, Query { limit: Some(3), after, ..Default::default() });
+    assert_eq!(vec!("7"), ids(&page));
+    assert_eq!(None, after);
+
+    // exactly at the limit, no further pages
+    let (page, after) = search_page(&barn, Query { limit: Some(7), ..Default::default() });
+    assert_eq!(7, page.len());
+    assert_eq!(None, after);
+
+    let (page, _) = search_page(&barn, Query { limit: Some(2), offset: 5, ..Default::default() });
+    assert_eq!(vec!("6", "7"), ids(&page));
+
+    // index backed
+    let mut q = Query::new("$[?(@.display_name == 'odd')]");
+    q.limit = Some(2);
+    let (page, after) = search_page(&barn, q);
+    assert_eq!(vec!("1", "3"), ids(&page));
+    let mut q = Query::new("$[?(@.display_name == 'odd')]");
+    q.limit = Some(2);
+    q.after = after;
+    let (page, after) = search_page(&barn, q);
+    assert_eq!(vec!("5", "7"), ids(&page));
+    assert_eq!(None, after);
+}
+
+#[test]
+fn test_sorted_search() {
+    let barn = open_barn_with("/tmp/barn_sorted", without_metadata);
+    let rows = vec!(("id1", "Delta", 1603171057300_i64), ("id2", "alpha", 1603171057100), ("id3", "Charlie", 1603171057400), ("id4", "bravo", 1603171057200), ("id5", "alpha", 1603171057500));
+    for (reg_id, display_name, created_at) in rows {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["display_name"] = Value::from(display_name);
+        r["created_at"] = Value::from(created_at);
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+
+    let q = Query { sort: Some(Sort::asc("display_name")), ..Default::default() };
+    assert_eq!(vec!("id2", "id5", "id4", "id3", "id1"), reg_ids(search_with(&barn, q)));
+
+    let q = Query { sort: Some(Sort::desc("created_at")), ..Default::default() };
+    assert_eq!(vec!("id5", "id3", "id1", "id4", "id2"), reg_ids(search_with(&barn, q)));
+
+    // candidates from one index ordered by another
+    let q = Query { sort: Some(Sort::desc("created_at")), conditions: vec!(Condition::eq("display_name", Value::from("Alpha"))), ..Default::default() };
+    assert_eq!(vec!("id5", "id2"), reg_ids(search_with(&barn, q)));
+
+    // range on the sort attribute combined with a filter and pagination
+    let mut q = Query::new("$[?(@.created_at > 1603171057100)]");
+    q.sort = Some(Sort::desc("created_at"));
+    q.limit = Some(2);
+    let (page, after) = search_page(&barn, q);
+    assert_eq!(vec!("id5", "id3"), reg_ids(page));
+    let mut q = Query::new("$[?(@.created_at > 1603171057100)]");
+    q.sort = Some(Sort::desc("created_at"));
+    q.after = after;
+    assert_eq!(vec!("id1", "id4"), reg_ids(search_with(&barn, q)));
+
+    // the next page starts after the position of the last resource even when it was deleted
+    let q = Query { sort: Some(Sort::desc("created_at")), limit: Some(1), ..Default::default() };
+    let (page, after) = search_page(&barn, q);
+    assert_eq!(vec!("id5"), reg_ids(page));
+    barn.delete(String::from("Business"), 5, &Precondition::default()).unwrap();
+    let q = Query { sort: Some(Sort::desc("created_at")), limit: Some(2), after, ..Default::default() };
+    assert_eq!(vec!("id3", "id1"), reg_ids(search_page(&barn, q).0));
+
+    // the resources missing from the sort index follow the indexed ones in the order of PKs
+    for reg_id in vec!("id6", "id7") {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["display_name"] = Value::from("alpha");
+        r.as_object_mut().unwrap().remove("created_at");
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+    let q = Query { sort: Some(Sort::desc("created_at")), ..Default::default() };
+    assert_eq!(vec!("id3", "id1", "id4", "id2", "id6", "id7"), reg_ids(search_with(&barn, q)));
+    let q = Query { sort: Some(Sort::asc("created_at")), conditions: vec!(Condition::eq("display_name", Value::from("alpha"))), ..Default::default() };
+    assert_eq!(vec!("id2", "id6", "id7"), reg_ids(search_with(&barn, q)));
+    // but not when the conditions on the sort attribute are looked up in its index
+    let q = Query { sort: Some(Sort::asc("created_at")), conditions: vec!(Condition::gt("created_at", Value::from(1603171057100_i64))), ..Default::default() };
+    assert_eq!(vec!("id4", "id1", "id3"), reg_ids(search_with(&barn, q)));
+
+    // pages of a single resource visit the same resources in the same order
+    for sort in vec!(Sort::asc("created_at"), Sort::desc("created_at"), Sort::asc("display_name"), Sort::desc("display_name")) {
+        let all = reg_ids(search_with(&barn, Query { sort: Some(sort.clone()), ..Default::default() }));
+        let mut paged = vec!();
+        let mut after = None;
+        loop {
+            let q = Query { sort: Some(sort.clone()), limit: Some(1), after, ..Default::default() };
+            let (page, next) = search_page(&barn, q);
+            paged.extend(reg_ids(page));
+            if next.is_none() {
+                break;
+            }
+            after = next;
+        }
+        assert_eq!(all, paged);
+        assert_eq!(6, paged.len());
+    }
+
+    let q = Query { sort: Some(Sort::asc("country_code")), ..Default::default() };
+    let (sn, _rc) = channel();
+    assert!(barn.search(String::from("Business"), q, sn).is_err());
+}
+
+#[test]
+fn test_search_projection() {
+    let barn = open_barn("/tmp/barn_projection");
+    let mut r = read_sample();
+    barn.insert(String::from("Business"), &mut r).unwrap();
+
+    let q = Query { fields: vec!(String::from("/id"), String::from("/display_name"), String::from("/location")), ..Default::default() };
+    let results = search_with(&barn, q);
+    assert_eq!(1, results.len());
+    assert_eq!(serde_json::json!({"id": "1", "display_name": "b1", "location": [90.0, 1]}), results[0]);
+}
+
+#[test]
+fn test_aggregate() {
+    let barn = open_barn("/tmp/barn_aggregate");
+    let rows = vec!(("id1", "Acme", "IN", 10), ("id2", "acme", "IN", 20), ("id3", "Other", "US", 30), ("id4", "Other", "US", 60));
+    for (reg_id, display_name, country_code, category_id) in rows {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["display_name"] = Value::from(display_name);
+        r["country_code"] = Value::from(country_code);
+        r["category_id"] = Value::from(category_id);
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+    let mut r = read_sample();
+    r["reg_id"] = Value::from("id5");
+    r.as_object_mut().unwrap().remove("display_name");
+    r.as_object_mut().unwrap().remove("category_id");
+    barn.insert(String::from("Business"), &mut r).unwrap();
+
+    let count = barn.aggregate(String::from("Business"), Query::default(), Aggregate::count()).unwrap();
+    assert_eq!(serde_json::json!({"count": 5}), count);
+
+    // served from the index
+    let expected = serde_json::json!([{"group": "acme", "count": 2}, {"group": "other", "count": 2}, {"group": null, "count": 1}]);
+    let count = barn.aggregate(String::from("Business"), Query::default(), Aggregate::count().group_by("display_name")).unwrap();
+    assert_eq!(expected, count);
+
+    // the same grouping when the resources are scanned
+    let count = barn.aggregate(String::from("Business"), Query::new("$[?(@.approved == false)]"), Aggregate::count().group_by("display_name")).unwrap();
+    assert_eq!(expected, count);
+
+    let avg = barn.aggregate(String::from("Business"), Query::default(), Aggregate::new(AggregateOp::Avg, "category_id").group_by("country_code")).unwrap();
+    assert_eq!(serde_json::json!([{"group": "IN", "count": 3, "avg": 15.0}, {"group": "US", "count": 2, "avg": 45.0}]), avg);
+
+    let q = Query { conditions: vec!(Condition::eq("country_code", Value::from("US"))), ..Default::default() };
+    let max = barn.aggregate(String::from("Business"), q, Aggregate::new(AggregateOp::Max, "category_id")).unwrap();
+    assert_eq!(serde_json::json!({"count": 2, "max": 60.0}), max);
+
+    let sum = barn.aggregate(String::from("Business"), Query::new("$[?(@.reg_id == 'id1')]"), Aggregate::new(AggregateOp::Sum, "category_id")).unwrap();
+    assert_eq!(serde_json::json!({"count": 1, "sum": 10.0}), sum);
+
+    let min = barn.aggregate(String::from("Business"), Query::new("$[?(@.reg_id == 'id5')]"), Aggregate::new(AggregateOp::Min, "category_id")).unwrap();
+    assert_eq!(serde_json::json!({"count": 1, "min": null}), min);
+}
+
+#[test]
+fn test_insert_many() {
+    let barn = open_barn("/tmp/barn_bulk");
+    let mut records = vec!();
+    for reg_id in vec!("id1", "id2", "id1", "id3", "id4") {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        records.push(r);
+    }
+
+    let results = barn.insert_many(String::from("Business"), &mut records, 0).unwrap();
+    assert_eq!(5, results.len());
+    assert_eq!(1, *results[0].as_ref().unwrap());
+    assert_eq!(2, *results[1].as_ref().unwrap());
+    match &results[2] {
+        Err(BarnError::UniqueConstraintViolation { index, attr_path, value, existing_id }) => {
+            assert_eq!("Business_reg_id", index);
+            assert_eq!("/reg_id", attr_path);
+            assert_eq!("id1", value.as_ref());
+            assert_eq!("1", **existing_id);
+        },
+        _ => assert!(false)
+    }
+    // the failed insert must not consume a PK or leave index entries behind
+    assert_eq!(3, *results[3].as_ref().unwrap());
+    assert_eq!(4, *results[4].as_ref().unwrap());
+    let count = barn.aggregate(String::from("Business"), Query::default(), Aggregate::count().group_by("display_name")).unwrap();
+    assert_eq!(serde_json::json!([{"group": "b1", "count": 4}]), count);
+
+    // batches of 2
+    let mut records = vec!();
+    for reg_id in vec!("id5", "id6", "id7") {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        records.push(r);
+    }
+    let results = barn.insert_many(String::from("Business"), &mut records, 2).unwrap();
+    let pks: Vec<u64> = results.into_iter().map(|r| r.unwrap()).collect();
+    assert_eq!(vec!(5, 6, 7), pks);
+    assert_eq!("7", records[2]["id"].as_str().unwrap());
+}
+
+#[test]
+fn test_transaction() {
+    let barn = open_barn("/tmp/barn_tx");
+    let mut r1 = read_sample();
+    barn.insert(String::from("Business"), &mut r1).unwrap();
+
+    // all the changes are committed together
+    let pks = barn.transaction(|txn| {
+        let mut r2 = read_sample();
+        r2["reg_id"] = Value::from("id2");
+        let pk2 = txn.insert(String::from("Business"), &mut r2)?;
+        let mut r1 = txn.get(1, String::from("Business"))?;
+        r1["display_name"] = Value::from("b1 updated");
+        txn.update(String::from("Business"), 1, &mut r1, &Precondition::default())?;
+        // changes are visible within the transaction
+        assert_eq!("b1 updated", txn.get(1, String::from("Business"))?["display_name"]);
+        Ok(vec!(pk2))
+    }).unwrap();
+    assert_eq!(vec!(2), pks);
+    assert_eq!("b1 updated", barn.get(1, String::from("Business")).unwrap()["display_name"]);
+    assert_eq!(1, search(&barn, "$[?(@.reg_id == 'id2')]").len());
+
+    // a failure rolls back all the changes
+    let tx_result: Result<(), BarnError> = barn.transaction(|txn| {
+        txn.delete(String::from("Business"), 2, &Precondition::default())?;
+        let mut r3 = read_sample();
+        r3["reg_id"] = Value::from("id3");
+        txn.insert(String::from("Business"), &mut r3)?;
+        // duplicate reg_id
+        let mut r4 = read_sample();
+        txn.insert(String::from("Business"), &mut r4)?;
+        Ok(())
+    });
+    match tx_result {
+        Err(BarnError::UniqueConstraintViolation { existing_id, .. }) => assert_eq!("1", *existing_id),
+        _ => assert!(false)
+    }
+    assert!(barn.get(2, String::from("Business")).is_ok());
+    assert!(barn.get(3, String::from("Business")).is_err());
+    assert_eq!(0, search(&barn, "$[?(@.reg_id == 'id3')]").len());
+
+    // a failed operation whose error is ignored leaves none of its writes behind
+    barn.transaction(|txn| {
+        let mut r3 = read_sample();
+        r3["reg_id"] = Value::from("id3");
+        txn.insert(String::from("Business"), &mut r3)?;
+        let mut r1 = txn.get(1, String::from("Business"))?;
+        r1["reg_id"] = Value::from("id3");
+        assert!(txn.update(String::from("Business"), 1, &mut r1, &Precondition::default()).is_err());
+        let mut r4 = read_sample();
+        r4["reg_id"] = Value::from("id2");
+        assert!(txn.insert(String::from("Business"), &mut r4).is_err());
+        Ok(())
+    }).unwrap();
+    assert_eq!(1, search(&barn, "$[?(@.reg_id == 'id1')]").len());
+    assert_eq!(1, search(&barn, "$[?(@.reg_id == 'id3')]").len());
+    // the index entry of the resource that failed to update is still there
+    let mut r5 = read_sample();
+    assert!(barn.insert(String::from("Business"), &mut r5).is_err());
+
+    let unknown: Result<Value, BarnError> = barn.transaction(|txn| txn.get(1, String::from("Unknown")));
+    assert!(unknown.is_err());
+}
+
+#[test]
+fn test_error_problem() {
+    use actix_web::ResponseError;
+    use barn::errors::Violation;
+
+    assert_eq!(404, BarnError::UnknownResourceName.status_code().as_u16());
+    assert_eq!(404, BarnError::ResourceNotFoundError.status_code().as_u16());
+    assert_eq!(500, BarnError::TxCommitError.status_code().as_u16());
+
+    let e = BarnError::UniqueConstraintViolation {
+        index: String::from("Business_reg_id"),
+        attr_path: String::from("/reg_id"),
+        value: Box::new(Value::from("id1")),
+        existing_id: Box::new(Value::from("1"))
+    };
+    let problem = e.to_problem();
+    assert_eq!(409, problem["status"]);
+    assert_eq!("Conflict", problem["title"]);
+    assert_eq!("Business_reg_id", problem["index"]);
+    assert_eq!("/reg_id", problem["attr_path"]);
+    assert_eq!("id1", problem["value"]);
+    assert_eq!("1", problem["existing_id"]);
+    assert_eq!(507, BarnError::DbFullError.status_code().as_u16());
+
+    let e = BarnError::SchemaValidationError(vec!(Violation { instance_path: String::from("/reg_id"), message: String::from("too short") }));
+    assert_eq!(422, e.status_code().as_u16());
+    let problem = e.to_problem();
+    assert_eq!(422, problem["status"]);
+    assert_eq!("/reg_id", problem["errors"][0]["instance_path"]);
+    assert_eq!("too short", problem["errors"][0]["message"]);
+    assert_eq!("application/problem+json", e.error_response().headers().get("content-type").unwrap());
+}
+
+#[test]
+fn test_versions() {
+    let barn = open_barn("/tmp/barn_versions");
+    let mut record = read_sample();
+    barn.insert(String::from("Business"), &mut record).unwrap();
+    let (mut stored, version) = barn.get_versioned(1, String::from("Business")).unwrap();
+    assert_eq!(barn::FIRST_VERSION, version);
+
+    let version = barn.update(String::from("Business"), 1, &mut stored, &Precondition::version(1)).unwrap();
+    assert_eq!(2, version);
+
+    // a stale version must not overwrite the latest one
+    stored["display_name"] = Value::from("stale");
+    match barn.update(String::from("Business"), 1, &mut stored, &Precondition::version(1)) {
+        Err(BarnError::PreconditionFailed(current)) => assert_eq!(2, current),
+        _ => assert!(false)
+    }
+    assert_eq!("b1", barn.get(1, String::from("Business")).unwrap()["display_name"]);
+
+    let if_none_match = Precondition { if_match: None, if_none_match: Some(VersionMatch::Versions(vec!(2))) };
+    assert!(barn.delete(String::from("Business"), 1, &if_none_match).is_err());
+    let any = Precondition { if_match: Some(VersionMatch::Any), if_none_match: None };
+    let (_, version) = barn.patch(String::from("Business"), 1, &any, |r| {
+        r["approved"] = Value::from(true);
+        Ok(())
+    }).unwrap();
+    assert_eq!(3, version);
+
+    assert!(barn.delete(String::from("Business"), 1, &Precondition::version(2)).is_err());
+    assert!(barn.delete(String::from("Business"), 1, &Precondition::version(3)).is_ok());
+    assert!(barn.get(1, String::from("Business")).is_err());
+}
+
+#[test]
+fn test_format_version() {
+    let barn = open_barn("/tmp/barn_format");
+    let mut record = read_sample();
+    barn.insert(String::from("Business"), &mut record).unwrap();
+    drop(barn);
+
+    let db_conf: DbConf = serde_json::from_reader(fs::File::open("config/db-conf.json").unwrap()).unwrap();
+    let barn = barn::Barn::open("/tmp/barn_format", &db_conf, fs::File::open("config/schema.json").unwrap()).unwrap();
+    assert_eq!("1", barn.get(1, String::from("Business")).unwrap()["id"]);
+    drop(barn);
+
+    // the environments written before the format was versioned are refused
+    let env_dir = "/tmp/barn_format_old";
+    let _ = fs::remove_dir_all(env_dir);
+    fs::create_dir_all(env_dir).unwrap();
+    let env = lmdb::Environment::new().set_max_dbs(10).open(std::path::Path::new(env_dir)).unwrap();
+    env.create_db(Some("Business"), lmdb::DatabaseFlags::INTEGER_KEY).unwrap();
+    drop(env);
+    let opened = barn::Barn::open(env_dir, &db_conf, fs::File::open("config/schema.json").unwrap());
+    assert!(matches!(opened, Err(BarnError::UnsupportedFormatVersion(1))));
+}
+
+#[test]
+fn test_metadata_stamping() {
+    let barn = open_barn("/tmp/barn_metadata");
+    let mut record = read_sample();
+    record["version"] = Value::from(10);
+    barn.insert(String::from("Business"), &mut record).unwrap();
+
+    let inserted = barn.get(1, String::from("Business")).unwrap();
+    let created_at = inserted["created_at"].as_i64().unwrap();
+    // the client supplied value is overridden
+    assert!(created_at > 1603171057002);
+    assert_eq!(created_at, inserted["updated_at"].as_i64().unwrap());
+    assert_eq!(1, inserted["version"]);
+
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    let mut changed = inserted.clone();
+    changed["created_at"] = Value::from(1603171057002_i64);
+    barn.update(String::from("Business"), 1, &mut changed, &Precondition::default()).unwrap();
+    let updated = barn.get(1, String::from("Business")).unwrap();
+    assert_eq!(created_at, updated["created_at"].as_i64().unwrap());
+    assert!(updated["updated_at"].as_i64().unwrap() > created_at);
+    assert_eq!(2, updated["version"]);
+
+    // the stamped creation time is indexed
+    let found = search_with(&barn, Query { conditions: vec!(Condition::eq("created_at", Value::from(created_at))), ..Default::default() });
+    assert_eq!(1, found.len());
+
+    // attributes of date-time format are stamped with RFC 3339 strings
+    let barn = open_barn_with("/tmp/barn_metadata_date_time", |db_conf| {
+        db_conf.resources.get_mut("Business").unwrap().metadata.created_at = Some(String::from("registered_at"));
+    });
+    let mut record = read_sample();
+    barn.insert(String::from("Business"), &mut record).unwrap();
+    let inserted = barn.get(1, String::from("Business")).unwrap();
+    let registered_at = inserted["registered_at"].as_str().unwrap();
+    let registered_at = chrono::DateTime::parse_from_rfc3339(registered_at).unwrap();
+    assert_eq!(registered_at.timestamp_millis(), inserted["updated_at"].as_i64().unwrap());
+
+    // times can't be stamped on the attributes of other types
+    let _ = fs::remove_dir_all("/tmp/barn_metadata_invalid");
+    let mut db_conf: DbConf = serde_json::from_reader(fs::File::open("config/db-conf.json").unwrap()).unwrap();
+    db_conf.resources.get_mut("Business").unwrap().metadata.updated_at = Some(String::from("display_name"));
+    let opened = barn::Barn::open("/tmp/barn_metadata_invalid", &db_conf, fs::File::open("config/schema.json").unwrap());
+    assert!(matches!(opened, Err(BarnError::DbConfigError)));
+}
+
+fn with_id_strategy(strategy: IdStrategy) -> impl FnOnce(&mut DbConf) {
+    move |db_conf: &mut DbConf| db_conf.resources.get_mut("Business").unwrap().id_strategy = strategy
+}
+
+#[test]
+fn test_client_ids() {
+    let barn = open_barn_with("/tmp/barn_client_ids", with_id_strategy(IdStrategy::Client));
+    let mut record = read_sample();
+    record["id"] = Value::from("b-1");
+    let pk = barn.insert(String::from("Business"), &mut record).unwrap();
+    assert_eq!("b-1", record["id"]);
+    assert_eq!(pk, barn.resolve_id(String::from("Business"), "b-1").unwrap());
+
+    let mut dup = read_sample();
+    dup["reg_id"] = Value::from("id2");
+    dup["id"] = Value::from("b-1");
+    match barn.insert(String::from("Business"), &mut dup) {
+        Err(BarnError::UniqueConstraintViolation { existing_id, .. }) => assert_eq!("b-1", *existing_id),
+        _ => assert!(false)
+    }
+
+    dup.as_object_mut().unwrap().remove("id");
+    match barn.insert(String::from("Business"), &mut dup) {
+        Err(BarnError::InvalidIdError) => assert!(true),
+        _ => assert!(false)
+    }
+
+    // the id cannot be changed by an update
+    let mut changed = barn.get(pk, String::from("Business")).unwrap();
+    changed["id"] = Value::from("b-2");
+    barn.update(String::from("Business"), pk, &mut changed, &Precondition::default()).unwrap();
+    assert_eq!("b-1", barn.get(pk, String::from("Business")).unwrap()["id"]);
+
+    barn.delete(String::from("Business"), pk, &Precondition::default()).unwrap();
+    assert!(barn.resolve_id(String::from("Business"), "b-1").is_err());
+    let mut reused = read_sample();
+    reused["id"] = Value::from("b-1");
+    assert!(barn.insert(String::from("Business"), &mut reused).is_ok());
+}
+
+#[test]
+fn test_generated_ids() {
+    for (dir, strategy) in vec!(("/tmp/barn_uuid_v4", IdStrategy::UuidV4), ("/tmp/barn_uuid_v7", IdStrategy::UuidV7), ("/tmp/barn_ulid", IdStrategy::Ulid)) {
+        let barn = open_barn_with(dir, with_id_strategy(strategy));
+        let mut first = read_sample();
+        first["id"] = Value::from("ignored");
+        let pk = barn.insert(String::from("Business"), &mut first).unwrap();
+        let mut second = read_sample();
+        second["reg_id"] = Value::from("id2");
+        barn.insert(String::from("Business"), &mut second).unwrap();
+
+        let id = first["id"].as_str().unwrap().to_string();
+        assert_ne!("ignored", id);
+        assert_ne!(id, second["id"].as_str().unwrap());
+        assert_eq!(pk, barn.resolve_id(String::from("Business"), &id).unwrap());
+        // generated ids are case insensitive
+        assert_eq!(pk, barn.resolve_id(String::from("Business"), &id.to_uppercase()).unwrap());
+        assert!(barn.resolve_id(String::from("Business"), "1").is_err());
+    }
+
+    let barn = open_barn("/tmp/barn_sequence_ids");
+    let mut record = read_sample();
+    barn.insert(String::from("Business"), &mut record).unwrap();
+    assert_eq!(1, barn.resolve_id(String::from("Business"), "1").unwrap());
+    assert!(barn.resolve_id(String::from("Business"), "x").is_err());
+}
+
+#[test]
+fn test_upsert() {
+    let barn = open_barn("/tmp/barn_upsert");
+    let mut record = read_sample();
+    let upserted = barn.upsert(String::from("Business"), "reg_id", &mut record).unwrap();
+    assert!(upserted.created);
+    assert_eq!(1, upserted.pk);
+    assert_eq!(1, upserted.version);
+
+    let mut changed = read_sample();
+    changed["display_name"] = Value::from("b1 renamed");
+    let upserted = barn.upsert(String::from("Business"), "reg_id", &mut changed).unwrap();
+    assert!(!upserted.created);
+    assert_eq!(1, upserted.pk);
+    assert_eq!(2, upserted.version);
+    assert_eq!("1", changed["id"]);
+    assert_eq!("b1 renamed", barn.get(1, String::from("Business")).unwrap()["display_name"]);
+
+    let mut other = read_sample();
+    other["reg_id"] = Value::from("id2");
+    let upserted = barn.upsert(String::from("Business"), "reg_id", &mut other).unwrap();
+    assert!(upserted.created);
+    assert_eq!(2, upserted.pk);
+
+    match barn.upsert(String::from("Business"), "display_name", &mut other) {
+        Err(BarnError::NoUniqueIndexError(attr)) => assert_eq!("display_name", attr),
+        _ => assert!(false)
+    }
+    other.as_object_mut().unwrap().remove("reg_id");
+    assert!(barn.upsert(String::from("Business"), "reg_id", &mut other).is_err());
+}
+
+#[test]
+fn test_get_by() {
+    let barn = open_barn_with("/tmp/barn_get_by", |db_conf| {
+        db_conf.resources.get_mut("Business").unwrap().indices.push(IndexConf { attr_path: AttrPath::Single(String::from("account_id")), unique: Some(true), index_missing: None, normalizer: None });
+    });
+    let mut record = read_sample();
+    record["reg_id"] = Value::from("ID1");
+    barn.insert(String::from("Business"), &mut record).unwrap();
+
+    // the value is normalized the same way as while indexing
+    let found = barn.get_by(String::from("Business"), "reg_id", &Value::from(" id1 ")).unwrap();
+    assert_eq!("1", found["id"]);
+    let found = barn.get_by(String::from("Business"), "account_id", &Value::from("1")).unwrap();
+    assert_eq!("1", found["id"]);
+    let found = barn.get_by(String::from("Business"), "account_id", &Value::from(1)).unwrap();
+    assert_eq!("1", found["id"]);
+
+    match barn.get_by(String::from("Business"), "reg_id", &Value::from("id2")) {
+        Err(BarnError::ResourceNotFoundError) => assert!(true),
+        _ => assert!(false)
+    }
+    match barn.get_by(String::from("Business"), "display_name", &Value::from("b1")) {
+        Err(BarnError::NoUniqueIndexError(_)) => assert!(true),
+        _ => assert!(false)
+    }
+    assert!(barn.get_by(String::from("Business"), "account_id", &Value::from("one")).is_err());
+
+    // nulls are left out of unique indices, they can be repeated and are still found by scanning
+    for reg_id in vec!("id2", "id3") {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["account_id"] = Value::Null;
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+    let q = Query { filter: None, conditions: vec!(Condition::eq("account_id", Value::Null)), ..Default::default() };
+    let found: Vec<Value> = search_with(&barn, q).iter().map(|r| r["reg_id"].clone()).collect();
+    assert_eq!(vec!("id2", "id3"), found);
+    assert!(barn.get_by(String::from("Business"), "account_id", &Value::Null).is_err());
+    let q = Query { sort: Some(Sort::asc("account_id")), ..Default::default() };
+    let sorted: Vec<Value> = search_with(&barn, q).iter().map(|r| r["reg_id"].clone()).collect();
+    assert_eq!(vec!("ID1", "id2", "id3"), sorted);
+}
+
+#[test]
+fn test_get_many() {
+    let barn = open_barn("/tmp/barn_get_many");
+    for reg_id in vec!("id1", "id2", "id3") {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+    barn.delete(String::from("Business"), 2, &Precondition::default()).unwrap();
+
+    let ids: Vec<String> = vec!("3", "2", "1", "x").into_iter().map(String::from).collect();
+    let values = barn.get_many(String::from("Business"), &ids).unwrap();
+    assert_eq!(4, values.len());
+    assert_eq!("3", values[0].0);
+    assert_eq!("id3", values[0].1.as_ref().unwrap()["reg_id"]);
+    assert_eq!("2", values[1].0);
+    assert!(values[1].1.is_none());
+    assert_eq!("id1", values[2].1.as_ref().unwrap()["reg_id"]);
+    assert_eq!("x", values[3].0);
+    assert!(values[3].1.is_none());
+    assert!(barn.get_many(String::from("Unknown"), &ids).is_err());
+}
+
+#[test]
+fn test_compound_index() {
+    let barn = open_barn_with("/tmp/barn_compound", |db_conf| {
+        let indices = &mut db_conf.resources.get_mut("Business").unwrap().indices;
+        let attr_path = AttrPath::Compound(vec!(String::from("country_code"), String::from("category_id")));
+        indices.push(IndexConf { attr_path, unique: Some(false), index_missing: None, normalizer: None });
+        let attr_path = AttrPath::Compound(vec!(String::from("account_id"), String::from("category_id")));
+        indices.push(IndexConf { attr_path, unique: Some(true), index_missing: None, normalizer: None });
+    });
+    // inserted out of order to make sure the results follow the order of the (country_code, category_id) index
+    for (account_id, country_code, category_id) in vec!((1, "IN", 30), (2, "US", 10), (3, "IN", 10), (4, "IN", 20), (5, "INDIA", 5)) {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(format!("id{}", account_id));
+        r["account_id"] = Value::from(account_id);
+        r["country_code"] = Value::from(country_code);
+        r["category_id"] = Value::from(category_id);
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+
+    // equality on the prefix, a longer string with the same prefix is not matched
+    assert_eq!(vec!("id3", "id4", "id1"), reg_ids(search(&barn, "$[?(@.country_code == 'IN')]")));
+    // equality on the prefix and a range on the last attribute
+    assert_eq!(vec!("id4", "id1"), reg_ids(search(&barn, "$[?(@.country_code == 'IN' && @.category_id > 10)]")));
+    let q = Query { filter: None, conditions: vec!(Condition::eq("country_code", Value::from("in")), Condition::le("category_id", Value::from(20))), ..Default::default() };
+    assert_eq!(vec!("id3", "id4"), reg_ids(search_with(&barn, q)));
+    let q = Query { filter: None, conditions: vec!(Condition::eq("country_code", Value::from("IN")), Condition::lt("category_id", Value::from(30)), Condition::ge("category_id", Value::from(20))), ..Default::default() };
+    assert_eq!(vec!("id4"), reg_ids(search_with(&barn, q)));
+    // equality on all the attributes
+    assert_eq!(vec!("id2"), reg_ids(search(&barn, "$[?(@.country_code == 'US' && @.category_id == 10)]")));
+    // a range on the leading attribute without any equality
+    let q = Query { filter: None, conditions: vec!(Condition::le("account_id", Value::from(2))), ..Default::default() };
+    assert_eq!(vec!("id1", "id2"), reg_ids(search_with(&barn, q)));
+    let q = Query { filter: None, conditions: vec!(Condition::gt("account_id", Value::from(2)), Condition::lt("account_id", Value::from(5))), ..Default::default() };
+    assert_eq!(vec!("id3", "id4"), reg_ids(search_with(&barn, q)));
+    // conditions only on a trailing attribute cannot use the index but are still applied
+    assert_eq!(vec!("id2", "id3"), reg_ids(search(&barn, "$[?(@.category_id == 10)]")));
+
+    // uniqueness applies to the tuple of values
+    let mut r = read_sample();
+    r["reg_id"] = Value::from("id6");
+    r["account_id"] = Value::from(2);
+    r["category_id"] = Value::from(10);
+    match barn.insert(String::from("Business"), &mut r) {
+        Err(BarnError::UniqueConstraintViolation { attr_path, value, existing_id, .. }) => {
+            assert_eq!("/account_id,/category_id", attr_path);
+            assert_eq!(Value::from(vec!(2, 10)), *value);
+            assert_eq!("2", *existing_id);
+        },
+        _ => assert!(false)
+    }
+    r["category_id"] = Value::from(11);
+    barn.insert(String::from("Business"), &mut r).unwrap();
+
+    // updates move the resource within the index
+    let mut r = barn.get(4, String::from("Business")).unwrap();
+    r["category_id"] = Value::from(40);
+    barn.update(String::from("Business"), 4, &mut r, &Precondition::default()).unwrap();
+    assert_eq!(vec!("id1", "id4"), reg_ids(search(&barn, "$[?(@.country_code == 'IN' && @.category_id >= 30)]")));
+    barn.delete(String::from("Business"), 1, &Precondition::default()).unwrap();
+    assert_eq!(vec!("id3", "id6", "id4"), reg_ids(search(&barn, "$[?(@.country_code == 'IN')]")));
+}
+
+#[test]
+fn test_multi_key_index() {
+    let barn = open_barn_with("/tmp/barn_multi_key", |db_conf| {
+        let indices = &mut db_conf.resources.get_mut("Business").unwrap().indices;
+        indices.push(IndexConf { attr_path: AttrPath::Single(String::from("tags")), unique: Some(false), index_missing: None, normalizer: None });
+        indices.push(IndexConf { attr_path: AttrPath::Single(String::from("location")), unique: Some(false), index_missing: None, normalizer: None });
+    });
+    for (reg_id, tags, location) in vec!(("id1", json!(["food", "cafe"]), json!([12.5, 77.5])), ("id2", json!(["cafe", "bar", "cafe"]), json!([40.7, -74.0])), ("id3", Value::Null, json!([0.0, 0.0])), ("id4", json!([]), json!([1.5, 103.8]))) {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["location"] = location;
+        if !tags.is_null() {
+            r["tags"] = tags;
+        }
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+
+    // each element has its own entry, repeated elements are returned once
+    assert_eq!(vec!("id1", "id2"), search_reg_ids(&barn, vec!(Condition::eq("tags", Value::from("cafe")))));
+    assert_eq!(vec!("id2"), search_reg_ids(&barn, vec!(Condition::eq("tags", Value::from("bar")))));
+    assert!(search_reg_ids(&barn, vec!(Condition::eq("tags", Value::from("pub")))).is_empty());
+    // every condition may be satisfied by a different element
+    assert_eq!(vec!("id1"), search_reg_ids(&barn, vec!(Condition::eq("tags", Value::from("cafe")), Condition::eq("tags", Value::from("food")))));
+    assert_eq!(vec!("id1", "id4"), search_reg_ids(&barn, vec!(Condition::gt("location", Value::from(50.0)))));
+    assert_eq!(vec!("id2"), search_reg_ids(&barn, vec!(Condition::lt("location", Value::from(0.0)))));
+    // resources with several elements in the range are visited once across the pages
+    let mut paged = vec!();
+    let mut after = None;
+    loop {
+        let q = Query { filter: None, conditions: vec!(Condition::gt("location", Value::from(-100.0))), limit: Some(1), after, ..Default::default() };
+        let (page, next) = search_page(&barn, q);
+        paged.extend(reg_ids(page));
+        if next.is_none() {
+            break;
+        }
+        after = next;
+    }
+    assert_eq!(search_reg_ids(&barn, vec!(Condition::gt("location", Value::from(-100.0)))), paged);
+    assert_eq!(4, paged.len());
+
+    // entries of the removed elements are cleaned up
+    let mut r = barn.get(2, String::from("Business")).unwrap();
+    r["tags"] = json!(["bar"]);
+    barn.update(String::from("Business"), 2, &mut r, &Precondition::default()).unwrap();
+    assert_eq!(vec!("id1"), search_reg_ids(&barn, vec!(Condition::eq("tags", Value::from("cafe")))));
+    assert_eq!(vec!("id2"), search_reg_ids(&barn, vec!(Condition::eq("tags", Value::from("bar")))));
+    barn.delete(String::from("Business"), 1, &Precondition::default()).unwrap();
+    assert!(search_reg_ids(&barn, vec!(Condition::eq("tags", Value::from("food")))).is_empty());
+}
+
+#[test]
+fn test_boolean_and_null_index() {
+    let barn = open_barn_with("/tmp/barn_boolean_null", |db_conf| {
+        let indices = &mut db_conf.resources.get_mut("Business").unwrap().indices;
+        indices.push(IndexConf { attr_path: AttrPath::Single(String::from("website")), unique: Some(false), index_missing: Some(true), normalizer: None });
+    });
+    for (reg_id, approved, website) in vec!(("id1", true, json!("a.com")), ("id2", false, Value::Null), ("id3", false, json!("b.com")), ("id4", true, json!(null))) {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["approved"] = Value::from(approved);
+        if reg_id == "id2" {
+            r.as_object_mut().unwrap().remove("display_name");
+        }
+        else {
+            r["website"] = website;
+        }
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+
+    assert_eq!(vec!("id2", "id3"), reg_ids(search(&barn, "$[?(@.approved == false)]")));
+    let q = Query { filter: None, conditions: vec!(Condition::eq("approved", Value::from(true))), ..Default::default() };
+    assert_eq!(vec!("id1", "id4"), reg_ids(search_with(&barn, q)));
+    let q = Query { filter: None, conditions: vec!(Condition::gt("approved", Value::from(false))), ..Default::default() };
+    assert_eq!(vec!("id1", "id4"), reg_ids(search_with(&barn, q)));
+
+    // explicit nulls and, with index_missing, missing attributes are indexed under null
+    let q = Query { filter: None, conditions: vec!(Condition::eq("website", Value::Null)), ..Default::default() };
+    assert_eq!(vec!("id2", "id4"), reg_ids(search_with(&barn, q)));
+    let q = Query { filter: None, conditions: vec!(Condition::eq("website", Value::Null), Condition::eq("approved", Value::from(false))), ..Default::default() };
+    assert_eq!(vec!("id2"), reg_ids(search_with(&barn, q)));
+    let q = Query { sort: Some(Sort::desc("website")), ..Default::default() };
+    assert_eq!(vec!("id3", "id1", "id4", "id2"), reg_ids(search_with(&barn, q)));
+    let q = Query { filter: None, conditions: vec!(Condition::gt("website", Value::Null)), ..Default::default() };
+    let (sn, _rc) = channel();
+    assert!(barn.search(String::from("Business"), q, sn).is_err());
+
+    // a string made of a zero byte does not share the key of null
+    let mut r = barn.get(1, String::from("Business")).unwrap();
+    r["website"] = Value::from("\0");
+    barn.update(String::from("Business"), 1, &mut r, &Precondition::default()).unwrap();
+    let q = Query { filter: None, conditions: vec!(Condition::eq("website", Value::Null)), ..Default::default() };
+    assert_eq!(vec!("id2", "id4"), reg_ids(search_with(&barn, q)));
+    let q = Query { filter: None, conditions: vec!(Condition::eq("website", Value::from("\0"))), ..Default::default() };
+    assert_eq!(vec!("id1"), reg_ids(search_with(&barn, q)));
+    let q = Query { sort: Some(Sort::asc("website")), ..Default::default() };
+    assert_eq!(vec!("id2", "id4", "id1", "id3"), reg_ids(search_with(&barn, q)));
+    r["website"] = Value::from("a.com");
+    barn.update(String::from("Business"), 1, &mut r, &Precondition::default()).unwrap();
+
+    // the index of display_name does not have the missing attribute, the resources are scanned instead
+    let mut r = barn.get(3, String::from("Business")).unwrap();
+    r["display_name"] = Value::Null;
+    barn.update(String::from("Business"), 3, &mut r, &Precondition::default()).unwrap();
+    let q = Query { filter: None, conditions: vec!(Condition::eq("display_name", Value::Null)), ..Default::default() };
+    assert_eq!(vec!("id2", "id3"), reg_ids(search_with(&barn, q)));
+
+    let count = barn.aggregate(String::from("Business"), Query::default(), Aggregate::count().group_by("approved")).unwrap();
+    assert_eq!(json!([{"group": false, "count": 2}, {"group": true, "count": 2}]), count);
+    let count = barn.aggregate(String::from("Business"), Query::default(), Aggregate::count().group_by("display_name")).unwrap();
+    assert_eq!(json!([{"group": "b1", "count": 2}, {"group": null, "count": 2}]), count);
+}
+
+#[test]
+fn test_range_excludes_nulls() {
+    let barn = open_barn_with("/tmp/barn_range_nulls", |db_conf| {
+        let indices = &mut db_conf.resources.get_mut("Business").unwrap().indices;
+        indices.push(IndexConf { attr_path: AttrPath::Single(String::from("category_id")), unique: Some(false), index_missing: None, normalizer: None });
+        indices.push(IndexConf { attr_path: AttrPath::Single(String::from("account_id")), unique: Some(false), index_missing: Some(true), normalizer: None });
+        let attr_path = AttrPath::Compound(vec!(String::from("country_code"), String::from("category_id")));
+        indices.push(IndexConf { attr_path, unique: Some(false), index_missing: None, normalizer: None });
+    });
+    for (reg_id, category_id, account_id) in vec!(("id1", json!(5), json!(1)), ("id2", Value::Null, Value::Null), ("id3", json!(20), json!(3))) {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["category_id"] = category_id;
+        if account_id.is_null() {
+            r.as_object_mut().unwrap().remove("account_id");
+        }
+        else {
+            r["account_id"] = account_id;
+        }
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+
+    // explicit nulls
+    assert_eq!(vec!("id1"), search_reg_ids(&barn, vec!(Condition::lt("category_id", Value::from(10)))));
+    assert_eq!(vec!("id1", "id3"), search_reg_ids(&barn, vec!(Condition::le("category_id", Value::from(20)))));
+    // missing attributes indexed as null
+    assert_eq!(vec!("id1"), search_reg_ids(&barn, vec!(Condition::le("account_id", Value::from(1)))));
+    // the trailing attribute of a compound index
+    assert_eq!(vec!("id1"), search_reg_ids(&barn, vec!(Condition::eq("country_code", Value::from("IN")), Condition::lt("category_id", Value::from(10)))));
+    assert_eq!(vec!("id1", "id3"), search_reg_ids(&barn, vec!(Condition::eq("country_code", Value::from("IN")), Condition::le("category_id", Value::from(20)))));
+}
+
+#[test]
+fn test_normalizers() {
+    let normalize = |index: &str, normalizer: &str| {
+        let index = String::from(index);
+        let normalizer = String::from(normalizer);
+        move |db_conf: &mut DbConf| {
+            let indices = &mut db_conf.resources.get_mut("Business").unwrap().indices;
+            let i = indices.iter_mut().find(|i| i.attr_path.name() == index).unwrap();
+            i.normalizer = Some(normalizer);
+        }
+    };
+    let barn = open_barn_with("/tmp/barn_normalizers", |db_conf| {
+        normalize("display_name", "nfkc_casefold, strip_accents, collapse_whitespace")(db_conf);
+        normalize("reg_id", "none")(db_conf);
+    });
+    for (reg_id, display_name) in vec!(("id1", "Café  Crème"), ("ID1", "CAFE CREME"), ("id3", "Straße")) {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["display_name"] = Value::from(display_name);
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+
+    // the values looked up are normalized the same way as the indexed values
+    assert_eq!(vec!("id1", "ID1"), search_reg_ids(&barn, vec!(Condition::eq("display_name", Value::from(" cafe creme")))));
+    assert_eq!(vec!("id3"), search_reg_ids(&barn, vec!(Condition::eq("display_name", Value::from("STRASSE")))));
+    assert_eq!(vec!("ID1"), search_reg_ids(&barn, vec!(Condition::eq("reg_id", Value::from("ID1")))));
+    assert!(barn.get_by(String::from("Business"), "reg_id", &Value::from("Id1")).is_err());
+    // the condition left to the resources when another index is used is normalized the same way
+    let conditions = vec!(Condition::eq("reg_id", Value::from("ID1")), Condition::eq("display_name", Value::from("cafe creme")));
+    assert_eq!(vec!("ID1"), search_reg_ids(&barn, conditions));
+    let conditions = vec!(Condition::eq("reg_id", Value::from("id1")), Condition::eq("display_name", Value::from("CAFÉ CRÈME")));
+    assert_eq!(vec!("id1"), search_reg_ids(&barn, conditions));
+
+    let count = barn.aggregate(String::from("Business"), Query::default(), Aggregate::count().group_by("display_name")).unwrap();
+    assert_eq!(json!([{"group": "cafe creme", "count": 2}, {"group": "strasse", "count": 1}]), count);
+
+    let _ = fs::remove_dir_all("/tmp/barn_bad_normalizer");
+    let mut db_conf: DbConf = serde_json::from_reader(fs::File::open("config/db-conf.json").unwrap()).unwrap();
+    normalize("display_name", "soundex")(&mut db_conf);
+    match barn::Barn::open("/tmp/barn_bad_normalizer", &db_conf, fs::File::open("config/schema.json").unwrap()) {
+        Err(BarnError::DbConfigError) => assert!(true),
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_prefix_search() {
+    let barn = open_barn_with("/tmp/barn_prefix", |db_conf| {
+        let attr_path = AttrPath::Compound(vec!(String::from("country_code"), String::from("display_name")));
+        db_conf.resources.get_mut("Business").unwrap().indices.push(IndexConf { attr_path, unique: Some(false), index_missing: None, normalizer: None });
+    });
+    let rows = vec!(("id1", json!("Acme Corp"), "IN"), ("id2", json!("acme"), "US"), ("id3", json!("Other"), "IN"), ("id4", json!("Acmeist"), "IN"), ("id5", json!("Ac"), "IN"), ("id6", Value::Null, "IN"));
+    for (reg_id, display_name, country_code) in rows {
+        let mut r = read_sample();
+        r["reg_id"] = Value::from(reg_id);
+        r["display_name"] = display_name;
+        r["country_code"] = Value::from(country_code);
+        r["website"] = Value::from(format!("https://{}.com", reg_id));
+        barn.insert(String::from("Business"), &mut r).unwrap();
+    }
+
+    // in the order of the normalized keys
+    assert_eq!(vec!("id2", "id1", "id4"), search_reg_ids(&barn, vec!(Condition::starts_with("display_name", "ACM"))));
+    assert_eq!(vec!("id5", "id2", "id1", "id4"), search_reg_ids(&barn, vec!(Condition::starts_with("display_name", "ac"))));
+    assert!(search_reg_ids(&barn, vec!(Condition::starts_with("display_name", "acmes"))).is_empty());
+    // trailing whitespace of the prefix is kept, leading whitespace is trimmed like in the values
+    assert_eq!(vec!("id1"), search_reg_ids(&barn, vec!(Condition::starts_with("display_name", " acme "))));
+    // the empty prefix matches all the strings but not null
+    assert_eq!(vec!("id5", "id2", "id1", "id4", "id3"), search_reg_ids(&barn, vec!(Condition::starts_with("display_name", ""))));
+    // equality on the leading attribute of a compound index and a prefix on the next one
+    assert_eq!(vec!("id1", "id4"), search_reg_ids(&barn, vec!(Condition::eq("country_code", Value::from("IN")), Condition::starts_with("display_name", "acm"))));
+    assert_eq!(vec!("id1"), search_reg_ids(&barn, vec!(Condition::eq("country_code", Value::from("IN")), Condition::starts_with("display_name", "ACME "))));
+    assert_eq!(vec!("id5", "id1", "id4", "id3"), search_reg_ids(&barn, vec!(Condition::eq("country_code", Value::from("IN")), Condition::starts_with("display_name", ""))));
+    // the prefix is normalized the same way when checked on the resources found using another index
+    assert_eq!(vec!("id1"), search_reg_ids(&barn, vec!(Condition::eq("reg_id", Value::from("id1")), Condition::starts_with("display_name", " ACM"))));
+    assert!(search_reg_ids(&barn, vec!(Condition::eq("reg_id", Value::from("id4")), Condition::starts_with("display_name", "acme "))).is_empty());
+    // combined with a sort on another attribute
+    let q = Query { conditions: vec!(Condition::starts_with("display_name", "acme")), sort: Some(Sort::desc("reg_id")), ..Default::default() };
+    let sorted: Vec<Value> = search_with(&barn, q).iter().map(|r| r["reg_id"].clone()).collect();
+    assert_eq!(vec!("id4", "id2", "id1"), sorted);
+
+    // attributes without a string index are scanned
+    assert_eq!(vec!("id3"), search_reg_ids(&barn, vec!(Condition::starts_with("website", "HTTPS://ID3"))));
+    assert!(search_reg_ids(&barn, vec!(Condition::starts_with("account_id", "1"))).is_empty());
+
+    let q = Query { filter: None, conditions: vec!(Condition::new("display_name", barn::query::Op::StartsWith, Value::from(1))), ..Default::default() };
+    let (sn, _rc) = channel();
+    assert!(barn.search(String::from("Business"), q, sn).is_err());
+}
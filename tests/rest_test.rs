@@ -0,0 +1,356 @@
+use std::fs;
+use std::sync::Arc;
+use actix_web::{test, App};
+use actix_web::http::StatusCode;
+use serde_json::{json, Value};
+use barn::AppData;
+
+fn app_data(env_dir: &str) -> AppData<'static> {
+    // cleanup
+    let _ = fs::remove_dir_all(env_dir);
+
+    let schema_file = fs::File::open("config/schema.json").unwrap();
+    let db_conf_file = fs::File::open("config/db-conf.json").unwrap();
+    let db_conf = serde_json::from_reader(db_conf_file).unwrap();
+    let barn = barn::Barn::open(env_dir, &db_conf, schema_file).unwrap();
+    let schema: &'static Value = Box::leak(barn.schema.clone());
+    let validator = jsonschema_valid::Config::from_schema(schema, None).unwrap();
+    AppData {
+        barn: Arc::new(barn),
+        validator: Arc::new(validator),
+        bulk_limit: 2048
+    }
+}
+
+/// Registers the services in the same order as the server
+macro_rules! init_app {
+    ($ad: expr) => {
+        test::init_service(App::new()
+            .data($ad)
+            .service(barn::transaction)
+            .service(barn::insert)
+            .service(barn::bulk_insert)
+            .service(barn::multi_get)
+            .service(barn::count)
+            .service(barn::aggregate)
+            .service(barn::get)
+            .service(barn::get_by)
+            .service(barn::search)
+            .service(barn::update)
+            .service(barn::upsert)
+            .service(barn::delete)
+            .service(barn::patch)
+        ).await
+    };
+}
+
+fn business(reg_id: &str) -> Value {
+    let mut r: Value = serde_json::from_str(&fs::read_to_string("config/samples/business.json").unwrap()).unwrap();
+    let r_obj = r.as_object_mut().unwrap();
+    r_obj.remove("id");
+    r_obj.insert(String::from("reg_id"), Value::from(reg_id));
+    r
+}
+
+#[actix_rt::test]
+async fn test_bulk_insert() {
+    let mut app = init_app!(app_data("/tmp/barn_rest_bulk"));
+
+    let body = format!("{}\n\n{}\r\n{}", business("b1"), business("b2"), business("b1"));
+    let req = test::TestRequest::post().uri("/Business/_bulk")
+        .header("Content-Type", "application/x-ndjson")
+        .set_payload(body)
+        .to_request();
+    let statuses: Value = test::read_response_json(&mut app, req).await;
+    let statuses: Vec<Value> = statuses.as_array().unwrap().iter().map(|s| s["status"].clone()).collect();
+    assert_eq!(vec!(json!(201), json!(201), json!(409)), statuses);
+
+    let req = test::TestRequest::post().uri("/Business/_bulk")
+        .set_json(&json!([business("b3")]))
+        .to_request();
+    let statuses: Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(201, statuses[0]["status"]);
+
+    // arrays are limited, newline delimited JSON is read line by line
+    let many: Vec<Value> = (10..20).map(|i| business(&format!("b{}", i))).collect();
+    let req = test::TestRequest::post().uri("/Business/_bulk")
+        .set_json(&json!(many))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, resp.status());
+    let problem: Value = test::read_body_json(resp).await;
+    assert_eq!(413, problem["status"]);
+
+    let body: Vec<String> = many.iter().map(|r| r.to_string()).collect();
+    let req = test::TestRequest::post().uri("/Business/_bulk")
+        .header("Content-Type", "application/x-ndjson")
+        .set_payload(body.join("\n"))
+        .to_request();
+    let statuses: Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(10, statuses.as_array().unwrap().len());
+
+}
+
+#[actix_rt::test]
+async fn test_malformed_bodies() {
+    let mut app = init_app!(app_data("/tmp/barn_rest_malformed"));
+    let req = test::TestRequest::post().uri("/Business").set_json(&business("b1")).to_request();
+    test::call_service(&mut app, req).await;
+
+    let requests = vec!(
+        test::TestRequest::post().uri("/Business/_bulk")
+            .header("Content-Type", "application/x-ndjson")
+            .set_payload(format!("{}\n{{", business("b2"))),
+        test::TestRequest::post().uri("/Business/_bulk")
+            .header("Content-Type", "application/json")
+            .set_payload("[{"),
+        test::TestRequest::patch().uri("/Business/1")
+            .header("Content-Type", "application/merge-patch+json")
+            .set_payload("{\"approved\""),
+        test::TestRequest::post().uri("/_tx")
+            .set_json(&json!([{"op": "insert", "resource": "Business"}]))
+    );
+    for req in requests {
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+        assert_eq!("application/problem+json", resp.headers().get("Content-Type").unwrap());
+        let problem: Value = test::read_body_json(resp).await;
+        assert_eq!(400, problem["status"]);
+        assert!(problem["detail"].as_str().unwrap().starts_with("malformed request body"));
+    }
+
+    let req = test::TestRequest::post().uri("/Business/_bulk")
+        .header("Content-Type", "application/x-ndjson")
+        .set_payload(format!("{}\n\n{{", business("b2")))
+        .to_request();
+    let problem: Value = test::read_response_json(&mut app, req).await;
+    assert!(problem["detail"].as_str().unwrap().contains("line 3"));
+}
+
+#[actix_rt::test]
+async fn test_get_preconditions() {
+    let mut app = init_app!(app_data("/tmp/barn_rest_get"));
+    let req = test::TestRequest::post().uri("/Business").set_json(&business("b1")).to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::CREATED, resp.status());
+
+    let get = |headers: &[(&str, &str)]| {
+        let mut req = test::TestRequest::get().uri("/Business/1");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.to_request()
+    };
+
+    let resp = test::call_service(&mut app, get(&[])).await;
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!("\"1\"", resp.headers().get("ETag").unwrap());
+
+    // the client's copy is current
+    for tag in &["\"1\"", "W/\"1\"", "\"3\", \"1\"", "*"] {
+        let resp = test::call_service(&mut app, get(&[("If-None-Match", tag)])).await;
+        assert_eq!(StatusCode::NOT_MODIFIED, resp.status(), "If-None-Match: {}", tag);
+        assert_eq!("\"1\"", resp.headers().get("ETag").unwrap());
+    }
+
+    // tags not issued by barn never match
+    for tag in &["\"2\"", "\"abc\"", ""] {
+        let resp = test::call_service(&mut app, get(&[("If-None-Match", tag)])).await;
+        assert_eq!(StatusCode::OK, resp.status(), "If-None-Match: {}", tag);
+    }
+
+    let resp = test::call_service(&mut app, get(&[("If-Match", "\"1\"")])).await;
+    assert_eq!(StatusCode::OK, resp.status());
+    for tag in &["\"2\"", "\"abc\""] {
+        let resp = test::call_service(&mut app, get(&[("If-Match", tag)])).await;
+        assert_eq!(StatusCode::PRECONDITION_FAILED, resp.status(), "If-Match: {}", tag);
+    }
+
+    // If-Match is evaluated first
+    let resp = test::call_service(&mut app, get(&[("If-Match", "\"1\""), ("If-None-Match", "\"1\"")])).await;
+    assert_eq!(StatusCode::NOT_MODIFIED, resp.status());
+    let resp = test::call_service(&mut app, get(&[("If-Match", "\"2\""), ("If-None-Match", "\"1\"")])).await;
+    assert_eq!(StatusCode::PRECONDITION_FAILED, resp.status());
+}
+
+#[actix_rt::test]
+async fn test_update() {
+    let mut app = init_app!(app_data("/tmp/barn_rest_update"));
+    let req = test::TestRequest::post().uri("/Business").set_json(&business("b1")).to_request();
+    test::call_service(&mut app, req).await;
+
+    let mut changed = business("b1");
+    changed["display_name"] = Value::from("changed");
+    let req = test::TestRequest::put().uri("/Business/1").set_json(&changed).to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!("\"2\"", resp.headers().get("ETag").unwrap());
+    let req = test::TestRequest::get().uri("/Business/1").to_request();
+    let stored: Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("changed", stored["display_name"]);
+
+    let req = test::TestRequest::put().uri("/Business/1").header("If-Match", "\"1\"").set_json(&changed).to_request();
+    assert_eq!(StatusCode::PRECONDITION_FAILED, test::call_service(&mut app, req).await.status());
+    let req = test::TestRequest::put().uri("/Business/2").set_json(&changed).to_request();
+    assert_eq!(StatusCode::NOT_FOUND, test::call_service(&mut app, req).await.status());
+    changed.as_object_mut().unwrap().remove("reg_id");
+    let req = test::TestRequest::put().uri("/Business/1").set_json(&changed).to_request();
+    assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, test::call_service(&mut app, req).await.status());
+}
+
+#[actix_rt::test]
+async fn test_delete() {
+    let mut app = init_app!(app_data("/tmp/barn_rest_delete"));
+    let req = test::TestRequest::post().uri("/Business").set_json(&business("b1")).to_request();
+    test::call_service(&mut app, req).await;
+
+    let req = test::TestRequest::delete().uri("/Business/1").header("If-Match", "\"2\"").to_request();
+    assert_eq!(StatusCode::PRECONDITION_FAILED, test::call_service(&mut app, req).await.status());
+    let req = test::TestRequest::delete().uri("/Business/1").to_request();
+    assert_eq!(StatusCode::NO_CONTENT, test::call_service(&mut app, req).await.status());
+
+    let req = test::TestRequest::get().uri("/Business/1").to_request();
+    assert_eq!(StatusCode::NOT_FOUND, test::call_service(&mut app, req).await.status());
+    let req = test::TestRequest::delete().uri("/Business/1").to_request();
+    assert_eq!(StatusCode::NOT_FOUND, test::call_service(&mut app, req).await.status());
+
+    // the unique value is released
+    let req = test::TestRequest::post().uri("/Business").set_json(&business("b1")).to_request();
+    assert_eq!(StatusCode::CREATED, test::call_service(&mut app, req).await.status());
+}
+
+#[actix_rt::test]
+async fn test_patch() {
+    let mut app = init_app!(app_data("/tmp/barn_rest_patch"));
+    let req = test::TestRequest::post().uri("/Business").set_json(&business("b1")).to_request();
+    test::call_service(&mut app, req).await;
+
+    let patch = |content_type: &str, body: Value| {
+        test::TestRequest::patch().uri("/Business/1")
+            .header("Content-Type", content_type)
+            .set_payload(body.to_string())
+            .to_request()
+    };
+
+    // the format of the patch document is selected by the content type
+    let req = patch("application/merge-patch+json", json!({"display_name": "merged"}));
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!("\"2\"", resp.headers().get("ETag").unwrap());
+    let patched: Value = test::read_body_json(resp).await;
+    assert_eq!("merged", patched["display_name"]);
+
+    let req = patch("application/json-patch+json", json!([{"op": "replace", "path": "/display_name", "value": "patched"}]));
+    let patched: Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("patched", patched["display_name"]);
+
+    // a merge patch document is not a valid JSON patch
+    let req = patch("application/json-patch+json", json!({"display_name": "merged"}));
+    assert_eq!(StatusCode::BAD_REQUEST, test::call_service(&mut app, req).await.status());
+    let req = patch("application/json-patch+json", json!([{"op": "remove", "path": "/missing"}]));
+    assert_eq!(StatusCode::BAD_REQUEST, test::call_service(&mut app, req).await.status());
+    let req = patch("application/json", json!({"display_name": "merged"}));
+    assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, test::call_service(&mut app, req).await.status());
+
+    // the patched resource is validated
+    let req = patch("application/merge-patch+json", json!({"reg_id": null}));
+    assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, test::call_service(&mut app, req).await.status());
+    let req = test::TestRequest::get().uri("/Business/1").to_request();
+    let stored: Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("b1", stored["reg_id"]);
+    assert_eq!(3, stored["version"]);
+}
+
+#[actix_rt::test]
+async fn test_transaction() {
+    let mut app = init_app!(app_data("/tmp/barn_rest_tx"));
+    let req = test::TestRequest::post().uri("/Business").set_json(&business("b1")).to_request();
+    test::call_service(&mut app, req).await;
+
+    let ops = json!([
+        {"op": "insert", "resource": "Business", "data": business("b2")},
+        {"op": "update", "resource": "Business", "id": "1", "version": 1, "data": business("b1")},
+        {"op": "get", "resource": "Business", "id": "1"}
+    ]);
+    let req = test::TestRequest::post().uri("/_tx").set_json(&ops).to_request();
+    let results: Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(json!({"status": 201, "id": "2", "version": 1}), results[0]);
+    assert_eq!(json!({"status": 200, "version": 2}), results[1]);
+    assert_eq!(2, results[2]["version"]);
+    assert_eq!("b1", results[2]["data"]["reg_id"]);
+
+    // a failed operation rolls back all the others
+    let ops = json!([
+        {"op": "delete", "resource": "Business", "id": "2"},
+        {"op": "insert", "resource": "Business", "data": business("b3")},
+        {"op": "update", "resource": "Business", "id": "1", "version": 1, "data": business("b1")}
+    ]);
+    let req = test::TestRequest::post().uri("/_tx").set_json(&ops).to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::PRECONDITION_FAILED, resp.status());
+    let problem: Value = test::read_body_json(resp).await;
+    assert_eq!(2, problem["op"]);
+
+    let req = test::TestRequest::get().uri("/Business/2").to_request();
+    assert_eq!(StatusCode::OK, test::call_service(&mut app, req).await.status());
+    let req = test::TestRequest::get().uri("/Business/3").to_request();
+    assert_eq!(StatusCode::NOT_FOUND, test::call_service(&mut app, req).await.status());
+}
+
+#[actix_rt::test]
+async fn test_insert_location() {
+    let mut app = init_app!(app_data("/tmp/barn_rest_insert"));
+    let req = test::TestRequest::post().uri("/Business").set_json(&business("b1")).to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::CREATED, resp.status());
+    assert_eq!("/Business/1", resp.headers().get("Location").unwrap());
+    assert_eq!("\"1\"", resp.headers().get("ETag").unwrap());
+    assert!(resp.headers().get("Preference-Applied").is_none());
+    let inserted: Value = test::read_body_json(resp).await;
+    assert_eq!("1", inserted["id"]);
+    assert_eq!("b1", inserted["reg_id"]);
+
+    let req = test::TestRequest::post().uri("/Business")
+        .header("Prefer", "respond-async, return=minimal")
+        .set_json(&business("b2"))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::CREATED, resp.status());
+    assert_eq!("/Business/2", resp.headers().get("Location").unwrap());
+    assert_eq!("return=minimal", resp.headers().get("Preference-Applied").unwrap());
+    assert!(test::read_body(resp).await.is_empty());
+}
+
+#[actix_rt::test]
+async fn test_search_pages() {
+    let mut app = init_app!(app_data("/tmp/barn_rest_search"));
+    for i in 1..=5 {
+        let mut r = business(&format!("b{}", i));
+        r["approved"] = Value::from(i != 3);
+        let req = test::TestRequest::post().uri("/Business").set_json(&r).to_request();
+        test::call_service(&mut app, req).await;
+    }
+
+    // the link to the next page keeps the conditions and drops the offset applied to the first page
+    let mut uri = String::from("/Business?attr=approved&eq=true&offset=1&limit=2");
+    let mut pages = vec!();
+    loop {
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let link = resp.headers().get("Link").map(|l| String::from(l.to_str().unwrap()));
+        let page: Value = test::read_body_json(resp).await;
+        pages.push(page.as_array().unwrap().iter().map(|r| r["reg_id"].clone()).collect::<Vec<Value>>());
+        match link {
+            Some(link) => {
+                assert!(link.ends_with(">; rel=\"next\""));
+                uri = String::from(link.trim_start_matches('<').split('>').next().unwrap());
+                assert!(uri.starts_with("/Business?attr=approved&eq=true&limit=2&cursor="));
+            },
+            None => break
+        }
+    }
+    assert_eq!(vec!(vec!(json!("b2"), json!("b4")), vec!(json!("b5"))), pages);
+
+    let req = test::TestRequest::get().uri("/Business?cursor=abc").to_request();
+    assert_eq!(StatusCode::BAD_REQUEST, test::call_service(&mut app, req).await.status());
+}